@@ -0,0 +1,231 @@
+// -*- rust -*-
+// mod dotflex::backup
+//
+// Target files that an operation is about to overwrite or append to are
+// copied to LOCAL/backups/<feature>/<unix-millis>/ first, laid out by their
+// unresolved target path (files outside of the target directory go under
+// `@abs`), so that `dotflex restore` can put them back.
+
+use std::cell::Cell;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::operation::OperationSchema;
+use super::util;
+use super::error::{DotflexError, Result};
+
+const ABSOLUTE_PREFIX: &str = "@abs";
+
+pub struct Backup {
+    feature: String,
+    stamp: Cell<u64>,
+    // whether the directory for `stamp` has been made, by this backup
+    claimed: Cell<bool>,
+}
+
+impl Backup {
+    /// Starts a new backup for `feature`, stamped with the current time.
+    /// Nothing is written until something is saved.
+    pub fn begin(feature: &str) -> Backup {
+        let stamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        Backup {
+            feature: feature.to_string(),
+            stamp: Cell::new(stamp),
+            claimed: Cell::new(false),
+        }
+    }
+
+    pub fn dir(&self) -> PathBuf {
+        backups_dir().join(&self.feature).join(self.stamp.get().to_string())
+    }
+
+    /// Makes the directory for this backup, moving on to a later stamp if
+    /// another run already has one with the same stamp.
+    fn claim(&self) -> Result<()> {
+        if self.claimed.get() {
+            return Ok(());
+        }
+        util::assure_path_to(self.dir())?;
+        loop {
+            let dir = self.dir();
+            match fs::create_dir(&dir) {
+                Ok(()) => break,
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists =>
+                    self.stamp.set(self.stamp.get() + 1),
+                Err(e) => return Err(DotflexError::io(&dir, e)),
+            }
+        }
+        self.claimed.set(true);
+        Ok(())
+    }
+
+    /// Backs up `target` if it exists; a missing target needs no backup.
+    pub fn save<T: AsRef<Path>>(&self, target: T) -> Result<()> {
+        let target = target.as_ref();
+        if fs::symlink_metadata(target).is_err() {
+            return Ok(());
+        }
+        self.claim()?;
+        let dest = self.dir().join(backup_relative(target));
+        if dest.exists() {
+            // already saved earlier in this run; keep the oldest contents
+            return Ok(());
+        }
+        util::assure_path_to(&dest)?;
+        copy_path(target, &dest)
+    }
+
+    /// Backs up whatever target file `op` would clobber, if any.
+    pub fn save_clobbered(&self, op: &OperationSchema) -> Result<()> {
+        match op {
+            OperationSchema::CopyFile { to, .. } => self.save(to),
+            OperationSchema::LinkFile { from, to, .. } => {
+                if util::links_to(to, from) {
+                    Ok(())
+                } else {
+                    self.save(to)
+                }
+            },
+            OperationSchema::Template { to, .. } => self.save(to),
+            OperationSchema::AppendToFile { to, .. } => self.save(to),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A single timestamped backup of a feature.
+pub struct BackupSet {
+    feature: String,
+    stamp: u64,
+    dir: PathBuf,
+}
+
+impl BackupSet {
+    pub fn feature(&self) -> &str {
+        self.feature.as_str()
+    }
+    pub fn stamp(&self) -> u64 {
+        self.stamp
+    }
+
+    /// Target paths contained in this backup, absolute.
+    pub fn targets(&self) -> Vec<PathBuf> {
+        let mut files = Vec::new();
+        walk(&self.dir, &mut files);
+        files.iter()
+            .map(|f| target_from_relative(f.strip_prefix(&self.dir).unwrap()))
+            .collect()
+    }
+
+    /// Copies the saved contents of `target` back into place.
+    pub fn restore<T: AsRef<Path>>(&self, target: T) -> Result<()> {
+        let target = target.as_ref();
+        let saved = self.dir.join(backup_relative(target));
+        if !saved.exists() {
+            return Err(DotflexError::io(&saved,
+                io::Error::from(io::ErrorKind::NotFound)));
+        }
+        util::assure_path_to(target)?;
+        util::remove_path(target)?;
+        copy_path(&saved, target)
+    }
+}
+
+/// Every backup on record, oldest first.
+pub fn list() -> Vec<BackupSet> {
+    let mut sets = Vec::new();
+    let features = match fs::read_dir(backups_dir()) {
+        Ok(rd) => rd,
+        Err(_) => return sets,
+    };
+    for feature in features.filter_map(|ent| ent.ok()) {
+        let stamps = match fs::read_dir(feature.path()) {
+            Ok(rd) => rd,
+            Err(_) => continue,
+        };
+        for stamp in stamps.filter_map(|ent| ent.ok()) {
+            let parsed = stamp.file_name().to_string_lossy().parse::<u64>();
+            if let Ok(parsed) = parsed {
+                sets.push(BackupSet {
+                    feature: feature.file_name().to_string_lossy().into_owned(),
+                    stamp: parsed,
+                    dir: stamp.path(),
+                });
+            }
+        }
+    }
+    sets.sort_by_key(|set| set.stamp);
+    sets
+}
+
+/// The most recent backup taken for `feature`.
+pub fn latest_for_feature(feature: &str) -> Option<BackupSet> {
+    list().into_iter()
+        .rfind(|set| set.feature == feature)
+}
+
+/// The most recent backup that contains `target`.
+pub fn latest_for_path<T: AsRef<Path>>(target: T) -> Option<BackupSet> {
+    let relative = backup_relative(target.as_ref());
+    list().into_iter()
+        .rfind(|set| set.dir.join(&relative).exists())
+}
+
+fn backups_dir() -> PathBuf {
+    util::local_path("backups")
+}
+
+/// Where `target` goes inside a backup.
+pub fn backup_relative(target: &Path) -> PathBuf {
+    let unresolved = util::unresolve_path_target(target);
+    if unresolved.is_absolute() {
+        let mut relative = PathBuf::from(ABSOLUTE_PREFIX);
+        relative.extend(unresolved.components()
+            .filter(|c| matches!(c, Component::Normal(_))));
+        relative
+    } else {
+        unresolved
+    }
+}
+
+fn target_from_relative(relative: &Path) -> PathBuf {
+    if relative.starts_with(ABSOLUTE_PREFIX) {
+        Path::new("/").join(relative.strip_prefix(ABSOLUTE_PREFIX).unwrap())
+    } else {
+        util::resolve_path_target(relative)
+    }
+}
+
+fn copy_path(from: &Path, to: &Path) -> Result<()> {
+    let output = Command::new("cp").arg("-R").arg(from).arg(to).output()
+        .map_err(|e| DotflexError::io(from, e))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(DotflexError::Command {
+            cmd: format!("cp -R {} {}", from.display(), to.display()),
+            status: output.status.code(),
+        })
+    }
+}
+
+fn walk(dir: &Path, files: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(rd) => rd,
+        Err(_) => return,
+    };
+    for entry in entries.filter_map(|ent| ent.ok()) {
+        let path = entry.path();
+        if path.is_dir() {
+            walk(&path, files);
+        } else {
+            files.push(path);
+        }
+    }
+}
@@ -0,0 +1,72 @@
+// -*- rust -*-
+// mod dotflex::condition
+//
+// `when:` conditions on features and operations. Every field that is given
+// must match for the condition to hold; string fields are glob patterns
+// (`*` and `?`), so e.g. `hostname: "work-*"` or `os: "linux"`.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::Path;
+
+use serde::{Serialize, Deserialize};
+
+use super::util;
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct Condition {
+    // as in std::env::consts::OS: linux, macos, freebsd, ...
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    os: Option<String>,
+    // the ID field of /etc/os-release: ubuntu, arch, fedora, ...
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    distro: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hostname: Option<String>,
+    // as in std::env::consts::ARCH: x86_64, aarch64, ...
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    arch: Option<String>,
+    // variable name -> pattern its value must match; "*" only requires that
+    // the variable is set
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    env: BTreeMap<String, String>,
+    // commands that must be found on $PATH
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    commands: Vec<String>,
+}
+
+impl Condition {
+    /// Whether the condition holds on the current host.
+    pub fn holds(&self) -> bool {
+        self.os.as_ref().map_or(true, |p| util::glob_match(p, env::consts::OS))
+            && self.arch.as_ref().map_or(true, |p| util::glob_match(p, env::consts::ARCH))
+            && self.hostname.as_ref().map_or(true, |p| util::glob_match(p, &util::hostname()))
+            && self.distro.as_ref().map_or(true, |p| match distro() {
+                Some(id) => util::glob_match(p, &id),
+                None => false,
+            })
+            && self.env.iter().all(|(name, p)| match env::var(name) {
+                Ok(value) => util::glob_match(p, &value),
+                Err(_) => false,
+            })
+            && self.commands.iter().all(|cmd| command_exists(cmd))
+    }
+}
+
+/// The distribution ID from /etc/os-release, if there is one.
+fn distro() -> Option<String> {
+    let release = fs::read_to_string("/etc/os-release").ok()?;
+    release.lines()
+        .find_map(|line| line.strip_prefix("ID="))
+        .map(|id| id.trim_matches('"').to_string())
+}
+
+fn command_exists(cmd: &str) -> bool {
+    if cmd.contains('/') {
+        return Path::new(cmd).is_file();
+    }
+    env::var_os("PATH")
+        .map(|paths| env::split_paths(&paths).any(|dir| dir.join(cmd).is_file()))
+        .unwrap_or(false)
+}
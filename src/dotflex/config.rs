@@ -0,0 +1,71 @@
+// -*- rust -*-
+// mod dotflex::config
+//
+// Per-machine dotflex settings, kept in LOCAL/config.yml. For now this is
+// just how the local repo is synced, as chosen by `dotflex init`.
+
+use std::fs;
+use std::path::PathBuf;
+
+use serde::{Serialize, Deserialize};
+
+use super::util;
+use super::error::{DotflexError, Result};
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct Config {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sync: Option<SyncConfig>,
+}
+
+/// Which sync backend the local repo uses, and where it syncs to.
+#[derive(Serialize, Deserialize, Clone)]
+pub enum SyncConfig {
+    #[serde(rename = "git")]
+    Git {
+        url: String,
+        /// name of the git remote for `url`
+        #[serde(default = "default_remote")]
+        remote: String,
+        /// branch to push to and pull from
+        #[serde(default = "default_branch")]
+        branch: String,
+    },
+    /// a plain directory, e.g. on a mounted drive
+    #[serde(rename = "directory")]
+    Directory {
+        path: PathBuf,
+    },
+}
+
+/// The remote name used when none is given or found.
+pub fn default_remote() -> String {
+    String::from("upstream")
+}
+
+/// The branch used when none is given or found.
+pub fn default_branch() -> String {
+    String::from("master")
+}
+
+impl Config {
+    pub fn load() -> Result<Config> {
+        let path = util::local_path("config.yml");
+        if !path.exists() {
+            return Ok(Config::default());
+        }
+        let contents = fs::read_to_string(&path)
+            .map_err(|e| DotflexError::io(&path, e))?;
+        serde_yaml::from_str(&contents)
+            .map_err(|e| DotflexError::yaml(&path, e))
+    }
+
+    pub fn dump(&self) -> Result<()> {
+        let path = util::local_path("config.yml");
+        util::assure_path_to(&path)?;
+        let contents = serde_yaml::to_string(self)
+            .map_err(|e| DotflexError::yaml(&path, e))?;
+        fs::write(&path, contents)
+            .map_err(|e| DotflexError::io(&path, e))
+    }
+}
@@ -0,0 +1,230 @@
+// -*- rust -*-
+// mod dotflex::conflict
+//
+// What to do when enabling a feature would copy a repo file over a target
+// file with different contents. Under ConflictPolicy::Ask the user picks
+// one of:
+//   - overwrite the target (after backing it up)
+//   - skip, leaving the target alone
+//   - show a diff of the two, and ask again
+//   - keep mine: copy the target into the repo instead
+//   - merge the two in $EDITOR, and use the result for both
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::error::{DotflexError, Result};
+use super::operation::OperationSchema;
+use super::util;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ConflictPolicy {
+    /// ask for every conflicting file
+    Ask,
+    /// overwrite the target without backing it up
+    Overwrite,
+    /// leave the target alone
+    Skip,
+    /// back the target up, then overwrite it
+    Backup,
+}
+
+impl Default for ConflictPolicy {
+    fn default() -> ConflictPolicy {
+        ConflictPolicy::Backup
+    }
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = DotflexError;
+    fn from_str(s: &str) -> Result<ConflictPolicy> {
+        match s {
+            "ask" => Ok(ConflictPolicy::Ask),
+            "overwrite" => Ok(ConflictPolicy::Overwrite),
+            "skip" => Ok(ConflictPolicy::Skip),
+            "backup" => Ok(ConflictPolicy::Backup),
+            _ => Err(DotflexError::Usage(format!(
+                "unknown conflict policy '{}': expected ask, overwrite, skip or backup", s))),
+        }
+    }
+}
+
+impl fmt::Display for ConflictPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", match self {
+            ConflictPolicy::Ask => "ask",
+            ConflictPolicy::Overwrite => "overwrite",
+            ConflictPolicy::Skip => "skip",
+            ConflictPolicy::Backup => "backup",
+        })
+    }
+}
+
+/// How a conflict was settled.
+pub enum Resolution {
+    /// go ahead with the operation, backing the target up first if `backup`
+    Overwrite { backup: bool },
+    /// leave the target as it is
+    Skip,
+    /// the target was kept and copied into the repo
+    KeepMine,
+    /// the target and repo copy were both replaced with a merge
+    Merged,
+}
+
+/// The repo and target files of `op`, if it would copy the one over the
+/// other while their contents differ. `op` must be resolved.
+pub fn conflicting(op: &OperationSchema) -> Option<(&Path, &Path)> {
+    match op {
+        OperationSchema::CopyFile { from, to, .. }
+            if from.is_file() && to.is_file() && !util::same_contents(from, to)
+            => Some((from.as_path(), to.as_path())),
+        _ => None,
+    }
+}
+
+/// Settles a conflict between `repo` and `target` according to `policy`.
+/// KeepMine and Merged have already written the files by the time this
+/// returns.
+pub fn resolve(repo: &Path, target: &Path, policy: ConflictPolicy) -> Result<Resolution> {
+    match policy {
+        ConflictPolicy::Overwrite => return Ok(Resolution::Overwrite { backup: false }),
+        ConflictPolicy::Skip => return Ok(Resolution::Skip),
+        ConflictPolicy::Backup => return Ok(Resolution::Overwrite { backup: true }),
+        ConflictPolicy::Ask => (),
+    }
+
+    let shown_target = util::unresolve_path_target(target);
+    let shown_repo = util::unresolve_path_repo(repo);
+    println!("{} differs from {}", shown_target.display(), shown_repo.display());
+    loop {
+        print!("  [o]verwrite, [s]kip, show [d]iff, [k]eep mine, [m]erge in $EDITOR? ");
+        let _ = io::stdout().flush();
+        let mut answer = String::new();
+        let read = io::stdin().lock().read_line(&mut answer)
+            .map_err(|e| DotflexError::io("<stdin>", e))?;
+        if read == 0 {
+            // no one to ask; leave the file alone
+            println!();
+            return Ok(Resolution::Skip);
+        }
+        match answer.trim() {
+            "o" | "overwrite" => return Ok(Resolution::Overwrite { backup: true }),
+            "s" | "skip" => return Ok(Resolution::Skip),
+            "d" | "diff" => {
+                let _ = Command::new("diff").arg("-u").arg(target).arg(repo).status();
+            },
+            "k" | "keep" => {
+                fs::copy(target, repo).map_err(|e| DotflexError::io(repo, e))?;
+                return Ok(Resolution::KeepMine);
+            },
+            "m" | "merge" => {
+                if merge(repo, target)? {
+                    return Ok(Resolution::Merged);
+                }
+            },
+            _ => (),
+        }
+    }
+}
+
+/// Creates a directory under the system temp directory that only the
+/// current user can read, failing rather than reusing one that exists.
+fn private_temp_dir() -> Result<PathBuf> {
+    let base = env::temp_dir();
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0);
+    let mut attempt = 0;
+    loop {
+        let dir = base.join(format!("dotflex-merge-{}-{}-{}", process::id(), nanos, attempt));
+        match fs::DirBuilder::new().mode(0o700).create(&dir) {
+            Ok(()) => return Ok(dir),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists && attempt < 100 =>
+                attempt += 1,
+            Err(e) => return Err(DotflexError::io(&dir, e)),
+        }
+    }
+}
+
+/// Opens both versions of a file, with conflict markers around the parts
+/// that differ, in $EDITOR; writes the result to both paths if the user
+/// resolved every conflict, and returns whether they did.
+fn merge(repo: &Path, target: &Path) -> Result<bool> {
+    let merged = marked_up(repo, target)?;
+    let merge_dir = private_temp_dir()?;
+    let merge_path = merge_dir.join(target.file_name().unwrap_or_else(|| "merge".as_ref()));
+    let written = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&merge_path)
+        .and_then(|mut file| file.write_all(merged.as_bytes()));
+    if let Err(e) = written {
+        let _ = fs::remove_dir_all(&merge_dir);
+        return Err(DotflexError::io(&merge_path, e));
+    }
+
+    let editor = env::var("VISUAL").or_else(|_| env::var("EDITOR"))
+        .unwrap_or_else(|_| String::from("vi"));
+    let mut words = editor.split_whitespace();
+    let status = Command::new(words.next().unwrap_or("vi"))
+        .args(words)
+        .arg(&merge_path)
+        .status();
+    let status = match status {
+        Ok(status) => status,
+        Err(e) => {
+            let _ = fs::remove_dir_all(&merge_dir);
+            return Err(DotflexError::io(&merge_path, e));
+        }
+    };
+    let result = fs::read_to_string(&merge_path)
+        .map_err(|e| DotflexError::io(&merge_path, e));
+    let _ = fs::remove_dir_all(&merge_dir);
+    let result = result?;
+
+    if !status.success() {
+        println!("  {} exited with {}; not merging", editor, status);
+        return Ok(false);
+    }
+    if result.lines().any(|line| line.starts_with("<<<<<<<") || line.starts_with(">>>>>>>")) {
+        println!("  there are still conflict markers in the merge; not merging");
+        return Ok(false);
+    }
+    fs::write(target, &result).map_err(|e| DotflexError::io(target, e))?;
+    fs::write(repo, &result).map_err(|e| DotflexError::io(repo, e))?;
+    Ok(true)
+}
+
+/// The target's contents, with the lines that differ from the repo copy
+/// replaced by conflict markers around both versions.
+fn marked_up(repo: &Path, target: &Path) -> Result<String> {
+    let mine = format!("<<<<<<< {}", util::unresolve_path_target(target).display());
+    let theirs = format!(">>>>>>> {}", util::unresolve_path_repo(repo).display());
+    let output = Command::new("diff")
+        .arg(format!("--old-group-format={}\n%<=======\n{}\n", mine, theirs))
+        .arg(format!("--new-group-format={}\n=======\n%>{}\n", mine, theirs))
+        .arg(format!("--changed-group-format={}\n%<=======\n%>{}\n", mine, theirs))
+        .arg("--unchanged-group-format=%=")
+        .arg(target)
+        .arg(repo)
+        .output();
+    match output {
+        // diff exits with 1 when the files differ, and 2 on trouble
+        Ok(output) if output.status.code() != Some(2) =>
+            Ok(String::from_utf8_lossy(&output.stdout).into_owned()),
+        _ => {
+            // a diff without group formats; mark up the files as a whole
+            let read = |path: &Path| fs::read_to_string(path)
+                .map_err(|e| DotflexError::io(path, e));
+            Ok(format!("{}\n{}=======\n{}{}\n", mine, read(target)?, read(repo)?, theirs))
+        },
+    }
+}
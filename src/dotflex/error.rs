@@ -0,0 +1,120 @@
+// -*- rust -*-
+// mod dotflex::error
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::shell::ShellResult;
+
+#[derive(Debug)]
+pub enum DotflexError {
+    /// A filesystem operation on `path` failed.
+    Io { path: PathBuf, source: io::Error },
+    /// A manifest or tracking file couldn't be parsed or serialized.
+    Yaml { path: PathBuf, source: serde_yaml::Error },
+    /// A git invocation couldn't be run or exited unsuccessfully.
+    Git { args: Vec<String>, detail: String },
+    /// A shell operation exited unsuccessfully; `status` is None if it was
+    /// killed by a signal.
+    Command { cmd: String, status: Option<i32> },
+    /// A shell or script operation failed or timed out.
+    Shell(ShellResult),
+    /// An operation failed; `op` describes the operation.
+    Operation { op: String, source: Box<DotflexError> },
+    /// An operation's source file doesn't exist.
+    NotViable { op: String },
+    /// A template couldn't be rendered.
+    Template { path: PathBuf, detail: String },
+    NoSuchFeature(String),
+    /// These files aren't bound by the feature.
+    NotBound { feature: String, files: Vec<PathBuf> },
+    /// Missing dependencies and dependency cycles among features.
+    Dependencies(Vec<String>),
+    /// Upstream changes to these target files conflict with local edits,
+    /// and the files were left alone.
+    MergeConflicts(Vec<PathBuf>),
+    /// No local repo has been set up with `dotflex init`.
+    NoRepo(PathBuf),
+    /// Bad arguments to a command.
+    Usage(String),
+}
+
+pub type Result<T> = std::result::Result<T, DotflexError>;
+
+impl DotflexError {
+    pub fn io<T: AsRef<Path>>(path: T, source: io::Error) -> DotflexError {
+        DotflexError::Io { path: path.as_ref().to_path_buf(), source }
+    }
+    pub fn yaml<T: AsRef<Path>>(path: T, source: serde_yaml::Error) -> DotflexError {
+        DotflexError::Yaml { path: path.as_ref().to_path_buf(), source }
+    }
+    pub fn git(args: &[&str], detail: String) -> DotflexError {
+        DotflexError::Git {
+            args: args.iter().map(|s| s.to_string()).collect(),
+            detail,
+        }
+    }
+    /// Attributes this error to the operation described by `op`.
+    pub fn in_operation<T: fmt::Display>(self, op: T) -> DotflexError {
+        DotflexError::Operation { op: op.to_string(), source: Box::new(self) }
+    }
+}
+
+impl fmt::Display for DotflexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DotflexError::Io { path, source } =>
+                write!(f, "{}: {}", path.display(), source),
+            DotflexError::Yaml { path, source } =>
+                write!(f, "couldn't parse {}: {}", path.display(), source),
+            DotflexError::Git { args, detail } =>
+                write!(f, "git {} failed: {}", args.join(" "), detail),
+            DotflexError::Command { cmd, status: Some(code) } =>
+                write!(f, "{} exited with status {}", cmd, code),
+            DotflexError::Command { cmd, status: None } =>
+                write!(f, "{} was killed by a signal", cmd),
+            DotflexError::Shell(result) =>
+                write!(f, "{}", result),
+            DotflexError::Operation { op, source } =>
+                write!(f, "{}: {}", op, source),
+            DotflexError::NotViable { op } =>
+                write!(f, "not viable: {}", op),
+            DotflexError::Template { path, detail } =>
+                write!(f, "couldn't render {}: {}", path.display(), detail),
+            DotflexError::NoSuchFeature(name) =>
+                write!(f, "no such feature: {}", name),
+            DotflexError::NotBound { feature, files } =>
+                write!(f, "not bound by feature {}: {}", feature,
+                    files.iter()
+                        .map(|p| p.display().to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")),
+            DotflexError::Dependencies(problems) =>
+                write!(f, "{}", problems.join("\n")),
+            DotflexError::MergeConflicts(paths) =>
+                write!(f, "upstream changes conflict with local edits to {}; \
+                    edit them by hand, or downsync with --conflict-markers",
+                    paths.iter()
+                        .map(|p| p.display().to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")),
+            DotflexError::NoRepo(path) =>
+                write!(f, "no local repo found at {}", path.display()),
+            DotflexError::Usage(msg) =>
+                write!(f, "{}", msg),
+        }
+    }
+}
+
+impl Error for DotflexError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DotflexError::Io { source, .. } => Some(source),
+            DotflexError::Yaml { source, .. } => Some(source),
+            DotflexError::Operation { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,235 @@
+// -*- rust -*-
+// mod dotflex::merge
+//
+// Three-way merging of upstream changes into target files the user has
+// edited. Whenever a copied file is installed, what was installed is kept
+// at LOCAL/bases/<feature>/, laid out like the backups; after a downsync,
+// that copy is the base that the edited target and the new repo copy are
+// merged against with `git merge-file`.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use super::backup;
+use super::error::{DotflexError, Result};
+use super::tracker::{FileRecord, InstalledFile};
+use super::transaction::Transaction;
+use super::util;
+
+/// What came of merging a single file.
+#[derive(Clone, Copy, PartialEq)]
+pub enum MergeOutcome {
+    /// only one side (or neither) changed since the file was installed
+    Unchanged,
+    /// upstream changes were merged into the target cleanly
+    Merged,
+    /// the target now has this many conflicts marked in it
+    Conflicts(usize),
+    /// there were this many conflicts, and the target was left alone
+    Stopped(usize),
+    /// both sides changed, but there's no base to merge against
+    NoBase,
+}
+
+fn bases_dir(feature: &str) -> PathBuf {
+    util::local_path("bases").join(feature)
+}
+
+/// Where the base of the target file `target` of `feature` is kept.
+pub fn base_path<T: AsRef<Path>>(feature: &str, target: T) -> PathBuf {
+    bases_dir(feature).join(backup::backup_relative(target.as_ref()))
+}
+
+/// Keeps a copy of what `file` installed into the target directory as its
+/// base. Only copied regular files have bases.
+pub fn save_base(feature: &str, file: &InstalledFile) -> Result<()> {
+    let target = match file.local_path() {
+        Some(path) if !file.is_link() => util::resolve_path_target(path),
+        _ => return Ok(()),
+    };
+    let base = base_path(feature, &target);
+    if !target.is_file() {
+        return util::remove_path(&base);
+    }
+    util::assure_path_to(&base)?;
+    fs::copy(&target, &base)
+        .map(|_| ())
+        .map_err(|e| DotflexError::io(&base, e))
+}
+
+/// Drops every base kept for `feature`.
+pub fn forget_bases(feature: &str) -> Result<()> {
+    util::remove_path(bases_dir(feature))
+}
+
+/// Merges changes to the repo copy of `file` since it was installed into
+/// its target, if the target has been edited too. With `markers`,
+/// conflicting changes are written into the target with conflict markers;
+/// otherwise such a target is left alone. After a merge, the repo copy
+/// becomes the new base, and `file`'s install record is updated to match.
+/// The target and base are guarded in `txn` before they are written.
+pub fn merge(txn: &mut Transaction, feature: &str, file: &mut InstalledFile, markers: bool) -> Result<MergeOutcome> {
+    let local_path = match file.local_path() {
+        Some(path) if !file.is_link() => path.to_path_buf(),
+        _ => return Ok(MergeOutcome::Unchanged),
+    };
+    let target = util::resolve_path_target(&local_path);
+    let repo = util::resolve_path_repo(file.repo_path());
+    let base = base_path(feature, &target);
+    let labels = [
+        util::unresolve_path_target(&target).display().to_string(),
+        util::unresolve_path_repo(&repo).display().to_string(),
+    ];
+    let outcome = merge_files(&target, &base, &repo, &labels, markers,
+        &mut |path| txn.guard(path))?;
+    if let MergeOutcome::Merged | MergeOutcome::Conflicts(_) = outcome {
+        file.set_installed(FileRecord::of(&base));
+    }
+    Ok(outcome)
+}
+
+/// Merges `target` with `repo` against `base`, as merge does; `labels`
+/// name the target and repo sides in conflict markers. `guard` is called
+/// on the target and the base just before they are written.
+fn merge_files(target: &Path, base: &Path, repo: &Path, labels: &[String; 2], markers: bool,
+    guard: &mut dyn FnMut(&Path) -> Result<()>) -> Result<MergeOutcome> {
+    if !target.is_file() || !repo.is_file() || util::same_contents(target, repo) {
+        return Ok(MergeOutcome::Unchanged);
+    }
+    if !base.is_file() {
+        return Ok(MergeOutcome::NoBase);
+    }
+    if util::same_contents(target, base) || util::same_contents(repo, base) {
+        return Ok(MergeOutcome::Unchanged);
+    }
+
+    let args = ["merge-file", "-p",
+        "-L", labels[0].as_str(), "-L", "installed", "-L", labels[1].as_str()];
+    let output = Command::new("git")
+        .args(args)
+        .arg(target)
+        .arg(base)
+        .arg(repo)
+        .output()
+        .map_err(|e| DotflexError::git(&args, e.to_string()))?;
+    // the exit status is the number of conflicts, or negative on errors
+    let conflicts = match output.status.code() {
+        Some(code) if (0..128).contains(&code) => code as usize,
+        _ => return Err(DotflexError::git(&args,
+            String::from_utf8_lossy(&output.stderr).trim().to_string())),
+    };
+    if conflicts > 0 && !markers {
+        return Ok(MergeOutcome::Stopped(conflicts));
+    }
+
+    guard(target)?;
+    guard(base)?;
+    fs::write(target, &output.stdout)
+        .map_err(|e| DotflexError::io(target, e))?;
+    fs::copy(repo, base)
+        .map_err(|e| DotflexError::io(base, e))?;
+    Ok(if conflicts > 0 {
+        MergeOutcome::Conflicts(conflicts)
+    } else {
+        MergeOutcome::Merged
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    /// A fresh directory holding a target, base and repo copy of a file.
+    struct Files {
+        dir: PathBuf,
+    }
+
+    impl Files {
+        fn new(name: &str, target: &str, base: Option<&str>, repo: &str) -> Files {
+            let dir = env::temp_dir().join(format!("dotflex-merge-test-{}-{}", process::id(), name));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            let files = Files { dir };
+            fs::write(files.target(), target).unwrap();
+            if let Some(base) = base {
+                fs::write(files.base(), base).unwrap();
+            }
+            fs::write(files.repo(), repo).unwrap();
+            files
+        }
+        fn target(&self) -> PathBuf { self.dir.join("target") }
+        fn base(&self) -> PathBuf { self.dir.join("base") }
+        fn repo(&self) -> PathBuf { self.dir.join("repo") }
+
+        /// Merges the files, returning the outcome and the paths guarded.
+        fn merge(&self, markers: bool) -> (MergeOutcome, Vec<PathBuf>) {
+            let mut guarded = Vec::new();
+            let labels = [String::from("target"), String::from("repo")];
+            let outcome = merge_files(&self.target(), &self.base(), &self.repo(), &labels, markers,
+                &mut |path| { guarded.push(path.to_path_buf()); Ok(()) })
+                .unwrap();
+            (outcome, guarded)
+        }
+        fn read(&self, path: PathBuf) -> String {
+            fs::read_to_string(path).unwrap()
+        }
+    }
+
+    impl Drop for Files {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn unchanged_when_only_one_side_changed() {
+        let files = Files::new("unchanged", "a\nB\nc\n", Some("a\nb\nc\n"), "a\nb\nc\n");
+        let (outcome, guarded) = files.merge(false);
+        assert!(outcome == MergeOutcome::Unchanged);
+        assert!(guarded.is_empty());
+        assert_eq!(files.read(files.target()), "a\nB\nc\n");
+    }
+
+    #[test]
+    fn merges_changes_to_different_lines() {
+        let files = Files::new("merged", "A\nb\nc\n", Some("a\nb\nc\n"), "a\nb\nC\n");
+        let (outcome, guarded) = files.merge(false);
+        assert!(outcome == MergeOutcome::Merged);
+        assert_eq!(guarded, vec![files.target(), files.base()]);
+        assert_eq!(files.read(files.target()), "A\nb\nC\n");
+        assert_eq!(files.read(files.base()), "a\nb\nC\n");
+    }
+
+    #[test]
+    fn marks_conflicts_when_asked() {
+        let files = Files::new("conflicts", "a\nX\nc\n", Some("a\nb\nc\n"), "a\nY\nc\n");
+        let (outcome, guarded) = files.merge(true);
+        assert!(outcome == MergeOutcome::Conflicts(1));
+        assert_eq!(guarded, vec![files.target(), files.base()]);
+        let merged = files.read(files.target());
+        assert!(merged.contains("<<<<<<< target") && merged.contains(">>>>>>> repo"));
+        assert_eq!(files.read(files.base()), "a\nY\nc\n");
+    }
+
+    #[test]
+    fn stops_on_conflicts_without_markers() {
+        let files = Files::new("stopped", "a\nX\nc\n", Some("a\nb\nc\n"), "a\nY\nc\n");
+        let (outcome, guarded) = files.merge(false);
+        assert!(outcome == MergeOutcome::Stopped(1));
+        assert!(guarded.is_empty());
+        assert_eq!(files.read(files.target()), "a\nX\nc\n");
+        assert_eq!(files.read(files.base()), "a\nb\nc\n");
+    }
+
+    #[test]
+    fn needs_a_base() {
+        let files = Files::new("nobase", "a\nX\nc\n", None, "a\nY\nc\n");
+        let (outcome, guarded) = files.merge(true);
+        assert!(outcome == MergeOutcome::NoBase);
+        assert!(guarded.is_empty());
+        assert_eq!(files.read(files.target()), "a\nX\nc\n");
+    }
+}
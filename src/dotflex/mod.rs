@@ -1,6 +1,16 @@
 pub mod common;
+pub mod error;
 pub mod operation;
 pub mod tracker;
 pub mod util;
 pub mod parser;
 pub mod sync;
+pub mod backup;
+pub mod template;
+pub mod condition;
+pub mod transaction;
+pub mod shell;
+pub mod conflict;
+pub mod merge;
+pub mod config;
+pub mod watch;
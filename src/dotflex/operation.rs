@@ -1,21 +1,19 @@
-#![allow(dead_code)]
-#![allow(unused_variables)]
-
 use std::convert::From;
-use std::ffi::{OsStr,OsString};
 use std::fmt;
 use std::fs;
-use std::fs::File;
-use std::io;
-use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::iter::Iterator;
+use std::collections::BTreeMap;
+use std::time::Duration;
 
 use serde::{Serialize, Deserialize};
 
-use super::common::output_verbose;
 use super::util;
+use super::template;
+use super::shell;
+use super::condition::Condition;
+use super::error::{DotflexError, Result};
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct OperationEffects {
@@ -73,6 +71,16 @@ impl OperationEffects {
 pub struct ShellInvocation {
     file: PathBuf,
     args: Vec<String>,
+    // directory to run in; relative paths are taken from the feature
+    // directory
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cwd: Option<PathBuf>,
+    // extra environment variables; a leading ~ in a value is expanded
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    env: BTreeMap<String, String>,
+    // program (and arguments) to run the file with, e.g. "python3"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    interpreter: Option<String>,
 }
 
 impl ShellInvocation {
@@ -80,7 +88,10 @@ impl ShellInvocation {
         ShellInvocation {
             file: PathBuf::from(path),
             args: args.iter().map(|s| String::from(*s))
-                .collect()
+                .collect(),
+            cwd: None,
+            env: BTreeMap::new(),
+            interpreter: None,
         }
     }
     pub fn args<'a>(&'a self) -> &'a Vec<String> {
@@ -89,10 +100,40 @@ impl ShellInvocation {
     pub fn file(&'_ self) -> &'_ Path {
         self.file.as_path()
     }
-}   
+    pub fn cwd(&self) -> Option<&Path> {
+        self.cwd.as_deref()
+    }
+    pub fn env(&self) -> &BTreeMap<String, String> {
+        &self.env
+    }
+    pub fn interpreter(&self) -> Option<&str> {
+        self.interpreter.as_deref()
+    }
+
+    /// The command that runs the file, through the interpreter if there is
+    /// one.
+    fn command(&self) -> Command {
+        let mut interpreter = self.interpreter.iter()
+            .flat_map(|s| s.split_whitespace());
+        let mut command = match interpreter.next() {
+            Some(program) => {
+                let mut command = Command::new(program);
+                command.args(interpreter).arg(&self.file);
+                command
+            },
+            None => Command::new(&self.file),
+        };
+        command.args(&self.args)
+            .envs(self.env.iter().map(|(name, value)| (name, util::expand_tilde(value))));
+        command
+    }
+}
 
 impl fmt::Display for ShellInvocation {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(interpreter) = &self.interpreter {
+            write!(f, "{} ", interpreter)?;
+        }
         write!( f, "{} {}",
             self.file.as_path().display(),
             self.args.iter()
@@ -104,6 +145,12 @@ impl fmt::Display for ShellInvocation {
 
 #[derive(Serialize, Deserialize)]
 pub struct FeatureSchema {
+    // names of features that must be enabled before this one
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    requires: Vec<String>,
+    // the feature only applies to hosts matching this condition
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    when: Option<Condition>,
     install: Vec<OperationSchema>,
     uninstall: Vec<OperationSchema>,
 }
@@ -111,37 +158,43 @@ pub struct FeatureSchema {
 impl FeatureSchema {
     pub fn new () -> FeatureSchema {
         FeatureSchema {
+            requires : Vec::new(),
+            when : None,
             install : Vec::new(),
             uninstall : Vec::new()
         }
     }
     pub fn install (install_: Vec<OperationSchema>) -> FeatureSchema {
         FeatureSchema {
+            requires : Vec::new(),
+            when : None,
             install : Vec::from(install_),
             uninstall: Vec::new()
         }
     }
 
+    pub fn requires(&self) -> &Vec<String> {
+        &self.requires
+    }
+    pub fn condition(&self) -> Option<&Condition> {
+        self.when.as_ref()
+    }
+    /// Whether this feature's `when` condition (if any) holds on this host.
+    pub fn applies(&self) -> bool {
+        self.when.as_ref().map_or(true, |c| c.holds())
+    }
+
     pub fn install_operations(&self) -> &Vec<OperationSchema> {
         &self.install
     }
     pub fn install_operations_mut(&mut self) -> &mut Vec<OperationSchema> {
         &mut self.install
     }
-
-    pub fn install_feature(&self) -> bool {
-        for schema in self.install.iter() {
-            if output_verbose() {
-                println!("Executing: {}", schema);
-            }
-            if !OperationInstance::from(schema).execute() {
-                return false
-            }
-        }
-        true
+    pub fn uninstall_operations(&self) -> &Vec<OperationSchema> {
+        &self.uninstall
     }
-    pub fn uninstall_feature(&self) -> bool {
-        unimplemented!();
+    pub fn uninstall_operations_mut(&mut self) -> &mut Vec<OperationSchema> {
+        &mut self.uninstall
     }
 }
 
@@ -150,149 +203,569 @@ pub enum OperationSchema {
     #[serde(rename = "copy_file")]
     CopyFile {
         from: PathBuf,
-        to: PathBuf, },
+        to: PathBuf,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        when: Option<Condition>, },
+    #[serde(rename = "link_file")]
+    LinkFile {
+        from: PathBuf,
+        to: PathBuf,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        when: Option<Condition>, },
+    #[serde(rename = "template")]
+    Template {
+        from: PathBuf,
+        to: PathBuf,
+        #[serde(default)]
+        vars: BTreeMap<String, String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        when: Option<Condition>, },
     #[serde(rename = "append_file")]
     AppendToFile {
         from: PathBuf,
-        to: PathBuf, },
+        to: PathBuf,
+        // what starts a comment in `to`, for the lines marking the
+        // appended block; "#" if not given
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        comment: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        when: Option<Condition>, },
     #[serde(rename = "shell")]
     ShellString {
         cmd: String,
-        effects: Option<OperationEffects>, },
+        effects: Option<OperationEffects>,
+        // seconds to let the command run before killing it
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        timeout: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        when: Option<Condition>, },
     #[serde(rename = "script")]
     ShellFile {
         cmd: ShellInvocation,
-        effects: Option<OperationEffects>, },
+        effects: Option<OperationEffects>,
+        // seconds to let the command run before killing it
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        timeout: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        when: Option<Condition>, },
 }
 
 impl OperationSchema {
     pub fn is_viable(&self) -> bool {
         match self {
-            OperationSchema::CopyFile { from, to } => from.exists(),
-            OperationSchema::AppendToFile {from, to} => from.exists(),
-            OperationSchema::ShellString {cmd, effects:_} => true,
-            OperationSchema::ShellFile {cmd, effects:_} => cmd.file.exists(),
+            OperationSchema::CopyFile { from, .. } => from.exists(),
+            OperationSchema::LinkFile { from, .. } => from.exists(),
+            OperationSchema::Template { from, .. } => from.exists(),
+            OperationSchema::AppendToFile { from, .. } => from.exists(),
+            OperationSchema::ShellString { .. } => true,
+            OperationSchema::ShellFile { cmd, effects:_, .. } => cmd.file.exists(),
+        }
+    }
+
+    /// The file or script an operation reads from, if any.
+    pub fn source(&self) -> Option<&Path> {
+        match self {
+            OperationSchema::CopyFile { from, .. } => Some(from.as_path()),
+            OperationSchema::LinkFile { from, .. } => Some(from.as_path()),
+            OperationSchema::Template { from, .. } => Some(from.as_path()),
+            OperationSchema::AppendToFile { from, .. } => Some(from.as_path()),
+            OperationSchema::ShellString { .. } => None,
+            OperationSchema::ShellFile { cmd, .. } => Some(cmd.file()),
+        }
+    }
+
+    /// The target path an operation puts a whole file at, if any; these are
+    /// removed again when the feature is disabled.
+    pub fn installed_target(&self) -> Option<&Path> {
+        match self {
+            OperationSchema::CopyFile { to, .. } => Some(to.as_path()),
+            OperationSchema::LinkFile { to, .. } => Some(to.as_path()),
+            OperationSchema::Template { to, .. } => Some(to.as_path()),
+            _ => None,
         }
     }
+
+    /// Resolves the paths of a manifest operation: sources against the repo
+    /// directory and destinations against the target directory.
+    pub fn resolved(&self) -> OperationSchema {
+        match self {
+            OperationSchema::CopyFile { from, to, when } =>
+                OperationSchema::CopyFile {
+                    from: util::resolve_path_repo(from),
+                    to: util::resolve_path_target(to),
+                    when: when.clone()
+                },
+            OperationSchema::LinkFile { from, to, when } =>
+                OperationSchema::LinkFile {
+                    from: util::resolve_path_repo(from),
+                    to: util::resolve_path_target(to),
+                    when: when.clone()
+                },
+            OperationSchema::Template { from, to, vars, when } =>
+                OperationSchema::Template {
+                    from: util::resolve_path_repo(from),
+                    to: util::resolve_path_target(to),
+                    vars: vars.clone(),
+                    when: when.clone()
+                },
+            OperationSchema::AppendToFile { from, to, comment, when } =>
+                OperationSchema::AppendToFile {
+                    from: util::resolve_path_repo(from),
+                    to: util::resolve_path_target(to),
+                    comment: comment.clone(),
+                    when: when.clone()
+                },
+            OperationSchema::ShellFile { cmd, effects, timeout, when } =>
+                OperationSchema::ShellFile {
+                    cmd: ShellInvocation {
+                        file: util::resolve_path_repo(cmd.file()),
+                        ..cmd.clone()
+                    },
+                    effects: effects.clone(),
+                    timeout: *timeout,
+                    when: when.clone()
+                },
+            OperationSchema::ShellString { cmd, effects, timeout, when } =>
+                OperationSchema::ShellString {
+                    cmd: cmd.clone(),
+                    effects: effects.clone(),
+                    timeout: *timeout,
+                    when: when.clone()
+                }
+        }
+    }
+
+    pub fn condition(&self) -> Option<&Condition> {
+        match self {
+            OperationSchema::CopyFile { when, .. } => when.as_ref(),
+            OperationSchema::LinkFile { when, .. } => when.as_ref(),
+            OperationSchema::Template { when, .. } => when.as_ref(),
+            OperationSchema::AppendToFile { when, .. } => when.as_ref(),
+            OperationSchema::ShellString { when, .. } => when.as_ref(),
+            OperationSchema::ShellFile { when, .. } => when.as_ref(),
+        }
+    }
+
+    /// Whether this operation's `when` condition (if any) holds on this host.
+    pub fn applies(&self) -> bool {
+        self.condition().map_or(true, |c| c.holds())
+    }
 }
 
 impl fmt::Display for OperationSchema {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            OperationSchema::CopyFile { from, to } =>
+            OperationSchema::CopyFile { from, to, .. } =>
                 write!(f, "copying {} to {}",
                     util::unresolve_path_repo(from)
                     .as_path().display(),
                     util::unresolve_path_target(to)
                     .as_path().display()),
-            OperationSchema::AppendToFile { from, to } =>
+            OperationSchema::LinkFile { from, to, .. } =>
+                write!(f, "linking {} to {}",
+                    util::unresolve_path_target(to)
+                    .as_path().display(),
+                    util::unresolve_path_repo(from)
+                    .as_path().display()),
+            OperationSchema::Template { from, to, .. } =>
+                write!(f, "render {} to {}",
+                    util::unresolve_path_repo(from)
+                    .as_path().display(),
+                    util::unresolve_path_target(to)
+                    .as_path().display()),
+            OperationSchema::AppendToFile { from, to, .. } =>
                 write!(f, "append {} to {}", 
                     util::unresolve_path_repo(from)
                     .as_path().display(),
                     util::unresolve_path_target(to)
                     .as_path().display()),
-            OperationSchema::ShellString { cmd, effects } =>
+            OperationSchema::ShellString { cmd, .. } =>
                 write!(f, "shell: [[{:?}]]", cmd),
-            OperationSchema::ShellFile { cmd, effects } =>
+            OperationSchema::ShellFile { cmd, .. } =>
                 write!(f, "shell: {}", cmd),
         }
     }
 }
 
+/// What executing an operation would do to a single path.
+#[derive(Clone, Copy, PartialEq)]
+pub enum PlannedEffect {
+    Create,
+    Overwrite,
+    Append,
+    Delete,
+}
+
+impl fmt::Display for PlannedEffect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlannedEffect::Create => write!(f, "create"),
+            PlannedEffect::Overwrite => write!(f, "overwrite"),
+            PlannedEffect::Append => write!(f, "append to"),
+            PlannedEffect::Delete => write!(f, "delete"),
+        }
+    }
+}
+
+impl PlannedEffect {
+    /// Create or Overwrite, depending on whether `path` already exists.
+    pub fn writing<T: AsRef<Path>>(path: T) -> PlannedEffect {
+        if path.as_ref().exists() {
+            PlannedEffect::Overwrite
+        } else {
+            PlannedEffect::Create
+        }
+    }
+}
+
 pub struct OperationInstance<'a> {
     schema: &'a OperationSchema,
+    // the feature the operation belongs to, if any
+    feature: Option<&'a str>,
 }
 
 impl<'a> From<&'a OperationSchema> for OperationInstance<'a> {
     fn from(schema: &'a OperationSchema) -> Self {
-        Self { schema: schema }
+        Self { schema: schema, feature: None }
     }
 }
 
 impl<'a> fmt::Display for OperationInstance<'a> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self.schema {
-            OperationSchema::CopyFile { from, to } =>
+            OperationSchema::CopyFile { from, to, .. } =>
                 write!(f, "copying {} to {}",
                     util::unresolve_path_repo(from)
                     .as_path().display(),
                     util::unresolve_path_target(to)
                     .as_path().display()),
-            OperationSchema::AppendToFile { from, to } =>
+            OperationSchema::LinkFile { from, to, .. } =>
+                write!(f, "linking {} to {}",
+                    util::unresolve_path_target(to)
+                    .as_path().display(),
+                    util::unresolve_path_repo(from)
+                    .as_path().display()),
+            OperationSchema::Template { from, to, .. } =>
+                write!(f, "rendering {} to {}",
+                    util::unresolve_path_repo(from)
+                    .as_path().display(),
+                    util::unresolve_path_target(to)
+                    .as_path().display()),
+            OperationSchema::AppendToFile { from, to, .. } =>
                 write!(f, "appending {} to {}", 
                     util::unresolve_path_repo(from)
                     .as_path().display(),
                     util::unresolve_path_target(to)
                     .as_path().display()),
-            OperationSchema::ShellString { cmd, effects } =>
+            OperationSchema::ShellString { .. } =>
                 write!(f, "executing shell command"),
-            OperationSchema::ShellFile { cmd, effects } =>
+            OperationSchema::ShellFile { cmd, .. } =>
                 write!(f, "executing file: {}", cmd),
         }
     }
 }
 
 impl<'a> OperationInstance<'a> {
-    pub fn execute(&self) -> bool {
-        if self.schema.is_viable() {
-            match self.schema {
-                OperationSchema::CopyFile { from, to } => {
-                    let parent_path_maybe = to.parent();
-                    if let Some(path) = parent_path_maybe {
-                        fs::create_dir_all(path).expect(format!(
-                            "Could not create directories necessary for path: {}",
-                            to.display()
-                        ).as_str());
-                    }
-                    if from.is_dir() {
-                        let res = fs::copy(from, to);
-                        if res.is_err() {
-                            // eprintln!("\nfailed: {}", res.unwrap_err());
-                            false
-                        } else {
-                            res.is_ok()
-                        }
-                    } else {
-                        let out = Command::new("cp").arg("-R").arg(from).arg(to).output();
-                        match out {
-                            Ok(output) => output.status.success(),
-                            Err(_) => false,
-                        }
-                    }
+    /// Runs the operation as part of `feature`, which shell operations are
+    /// told about through DOTFLEX_FEATURE and DOTFLEX_FEATURE_DIR.
+    pub fn in_feature(self, feature: &'a str) -> Self {
+        Self { feature: Some(feature), ..self }
+    }
+
+    pub fn schema(&self) -> &OperationSchema {
+        self.schema
+    }
+
+    /// The environment shell operations run with, on top of dotflex's own.
+    pub fn environment(&self) -> Vec<(&'static str, PathBuf)> {
+        let mut vars = vec![
+            ("DOTFLEX_REPO", util::repo_dir()),
+            ("DOTFLEX_TARGET", util::target_dir().to_path_buf()),
+            ("DOTFLEX_LOCAL", util::local_dir()),
+        ];
+        if let Some(feature) = self.feature {
+            vars.push(("DOTFLEX_FEATURE", PathBuf::from(feature)));
+            vars.push(("DOTFLEX_FEATURE_DIR", self.feature_dir()));
+        }
+        vars
+    }
+
+    fn feature_dir(&self) -> PathBuf {
+        match self.feature {
+            Some(feature) => util::repo_path("features").join(feature),
+            None => util::repo_dir(),
+        }
+    }
+
+    /// Lists the paths this operation would touch, without touching them.
+    /// Shell operations can only report what their `effects` declare.
+    pub fn plan(&self) -> Vec<(PathBuf, PlannedEffect)> {
+        match self.schema {
+            OperationSchema::CopyFile { to, .. } =>
+                vec![(to.clone(), PlannedEffect::writing(to))],
+            OperationSchema::LinkFile { to, .. } =>
+                vec![(to.clone(), PlannedEffect::writing(to))],
+            OperationSchema::Template { to, .. } =>
+                vec![(to.clone(), PlannedEffect::writing(to))],
+            OperationSchema::AppendToFile { to, .. } =>
+                vec![(to.clone(), if to.exists() {
+                    PlannedEffect::Append
+                } else {
+                    PlannedEffect::Create
+                })],
+            OperationSchema::ShellString { cmd: _, effects, .. }
+                | OperationSchema::ShellFile { cmd: _, effects, .. } => {
+                let effects = match effects {
+                    Some(effects) => effects,
+                    None => return Vec::new(),
+                };
+                let mut planned = Vec::new();
+                for path in effects.generates_list() {
+                    let path = util::resolve_path_target(path);
+                    let effect = PlannedEffect::writing(&path);
+                    planned.push((path, effect));
                 }
-                OperationSchema::ShellString { cmd, effects: _ } => {
-                    let out = Command::new("sh").arg("-c").arg(cmd).output();
-                    match out {
-                        Ok(output) => output.status.success(),
-                        Err(_) => false,
-                    }
+                for path in effects.clobbers_list() {
+                    planned.push((util::resolve_path_target(path), PlannedEffect::Overwrite));
                 }
-                OperationSchema::ShellFile { cmd, effects: _ } => {
-                    let out = Command::new(&cmd.file).args(&cmd.args).output();
-                    match out {
-                        Ok(output) => output.status.success(),
-                        Err(_) => false,
-                    }
+                for path in effects.deletes_list() {
+                    planned.push((util::resolve_path_target(path), PlannedEffect::Delete));
                 }
-                OperationSchema::AppendToFile { from, to } => {
-                    let mut to_file = fs::OpenOptions::new()
-                        .create(true)
-                        .write(true)
-                        .append(true)
-                        .open(to)
-                        .or_else(|e| -> io::Result<File> { panic!("could not open or create file {}: {}", to.as_path().display(), e) })
-                        .unwrap();
-                    to_file.write_all(
-                        fs::read(from)
-                            .or_else(|e| -> io::Result<Vec<u8>> { panic!("could not open file {}: {}", from.as_path().display(), e) })
-                            .unwrap()
-                            .as_slice())
-                        .is_ok()
+                planned
+            }
+        }
+    }
+
+    pub fn execute(&self) -> Result<()> {
+        if !self.schema.is_viable() {
+            return Err(DotflexError::NotViable { op: self.schema.to_string() });
+        }
+        match self.schema {
+            OperationSchema::CopyFile { from, to, .. } => {
+                util::assure_path_to(to)?;
+                if from.is_dir() {
+                    run_command(Command::new("cp").arg("-R").arg(from).arg(to),
+                        format!("cp -R {} {}", from.display(), to.display()))
+                } else {
+                    fs::copy(from, to)
+                        .map(|_| ())
+                        .map_err(|e| DotflexError::io(to, e))
                 }
             }
-        } else {
-            // println!("not viable");
-            false
+            OperationSchema::LinkFile { from, to, .. } => {
+                if util::links_to(to, from) {
+                    return Ok(());
+                }
+                util::assure_path_to(to)?;
+                util::remove_path(to)?;
+                std::os::unix::fs::symlink(from, to)
+                    .map_err(|e| DotflexError::io(to, e))
+            }
+            OperationSchema::Template { from, to, vars, .. } => {
+                let rendered = template::render_file(from, vars)?;
+                util::assure_path_to(to)?;
+                fs::write(to, rendered)
+                    .map_err(|e| DotflexError::io(to, e))
+            }
+            OperationSchema::ShellString { cmd, effects: _, timeout, .. } => {
+                let mut command = Command::new("sh");
+                command.arg("-c").arg(cmd).envs(self.environment());
+                run_shell(&mut command, cmd, *timeout)
+            }
+            OperationSchema::ShellFile { cmd, effects: _, timeout, .. } => {
+                let mut command = cmd.command();
+                command.envs(self.environment());
+                if let Some(cwd) = cmd.cwd() {
+                    command.current_dir(util::resolve_common(cwd)
+                        .unwrap_or(self.feature_dir().join(cwd)));
+                }
+                run_shell(&mut command, &cmd.to_string(), *timeout)
+            }
+            OperationSchema::AppendToFile { from, to, comment, .. } => {
+                let mut content = fs::read(from)
+                    .map_err(|e| DotflexError::io(from, e))?;
+                if !content.is_empty() && !content.ends_with(b"\n") {
+                    content.push(b'\n');
+                }
+                let (begin, end) = self.block_markers(from, comment);
+                let existing = if to.exists() {
+                    fs::read(to).map_err(|e| DotflexError::io(to, e))?
+                } else {
+                    Vec::new()
+                };
+                let mut block = Vec::new();
+                block.extend_from_slice(&begin);
+                block.push(b'\n');
+                block.extend_from_slice(&content);
+                block.extend_from_slice(&end);
+                block.push(b'\n');
+                // a copy appended before blocks were marked, still at the
+                // end of the file, is wrapped rather than appended again
+                let found = find_block(&existing, &begin, &end)
+                    .or_else(|| find_tail(&existing, &content));
+                let mut updated = Vec::with_capacity(existing.len() + block.len());
+                match found {
+                    Some((start, stop)) => {
+                        updated.extend_from_slice(&existing[..start]);
+                        updated.extend_from_slice(&block);
+                        updated.extend_from_slice(&existing[stop..]);
+                    }
+                    None => {
+                        updated.extend_from_slice(&existing);
+                        if !existing.is_empty() && !existing.ends_with(b"\n") {
+                            updated.push(b'\n');
+                        }
+                        updated.extend_from_slice(&block);
+                    }
+                }
+                util::assure_path_to(to)?;
+                fs::write(to, updated)
+                    .map_err(|e| DotflexError::io(to, e))
+            }
         }
     }
+
+    /// The lines an AppendToFile operation wraps its block in, naming the
+    /// feature and the source file.
+    fn block_markers(&self, from: &Path, comment: &Option<String>) -> (Vec<u8>, Vec<u8>) {
+        let comment = comment.as_deref().unwrap_or("#");
+        let label = match self.feature {
+            Some(feature) => format!("dotflex: {} ({})", feature,
+                util::unresolve_path_repo(from).display()),
+            None => format!("dotflex: {}", util::unresolve_path_repo(from).display()),
+        };
+        (format!("{} >>> {} >>>", comment, label).into_bytes(),
+            format!("{} <<< {} <<<", comment, label).into_bytes())
+    }
+
+    /// Removes the block an AppendToFile operation added to its target;
+    /// returns false if there was no such block. Does nothing for other
+    /// operations.
+    pub fn remove_appended(&self) -> Result<bool> {
+        let (from, to, comment) = match self.schema {
+            OperationSchema::AppendToFile { from, to, comment, .. } => (from, to, comment),
+            _ => return Ok(false),
+        };
+        if !to.exists() {
+            return Ok(false);
+        }
+        let (begin, end) = self.block_markers(from, comment);
+        let mut existing = fs::read(to)
+            .map_err(|e| DotflexError::io(to, e))?;
+        match find_block(&existing, &begin, &end) {
+            Some((start, stop)) => {
+                existing.drain(start..stop);
+                fs::write(to, existing)
+                    .map_err(|e| DotflexError::io(to, e))?;
+                Ok(true)
+            },
+            None => Ok(false),
+        }
+    }
+}
+
+/// Finds the byte range of the block from the line `begin` through the
+/// line `end` in `text`, including the end line's newline.
+fn find_block(text: &[u8], begin: &[u8], end: &[u8]) -> Option<(usize, usize)> {
+    let mut offset = 0;
+    let mut start = None;
+    for line in text.split_inclusive(|&b| b == b'\n') {
+        let trimmed = trim_end(line);
+        if start.is_none() && trimmed == begin {
+            start = Some(offset);
+        } else if start.is_some() && trimmed == end {
+            return Some((start.unwrap(), offset + line.len()));
+        }
+        offset += line.len();
+    }
+    None
+}
+
+/// Finds the byte range of `lines`, which ends in a newline, if `text`
+/// ends with it as whole lines; the final newline may be missing.
+fn find_tail(text: &[u8], lines: &[u8]) -> Option<(usize, usize)> {
+    if lines.is_empty() {
+        return None;
+    }
+    let bare = &lines[..lines.len() - 1];
+    let start = if text.ends_with(lines) {
+        text.len() - lines.len()
+    } else if text.ends_with(bare) {
+        text.len() - bare.len()
+    } else {
+        return None;
+    };
+    if start == 0 || text[start - 1] == b'\n' {
+        Some((start, text.len()))
+    } else {
+        None
+    }
+}
+
+fn trim_end(line: &[u8]) -> &[u8] {
+    let len = line.iter()
+        .rposition(|b| !b.is_ascii_whitespace())
+        .map_or(0, |i| i + 1);
+    &line[..len]
+}
+
+fn run_command(command: &mut Command, description: String) -> Result<()> {
+    let output = command.output()
+        .map_err(|e| DotflexError::io(command.get_program(), e))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(DotflexError::Command { cmd: description, status: output.status.code() })
+    }
+}
+
+fn run_shell(command: &mut Command, description: &str, timeout: Option<u64>) -> Result<()> {
+    let result = shell::run(command, description, timeout.map(Duration::from_secs))?;
+    if result.success() {
+        Ok(())
+    } else {
+        Err(DotflexError::Shell(result))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_block, find_tail};
+
+    const BEGIN: &[u8] = b"# >>> dotflex: zsh (features/zsh/aliases) >>>";
+    const END: &[u8] = b"# <<< dotflex: zsh (features/zsh/aliases) <<<";
+
+    #[test]
+    fn finds_a_marked_block() {
+        let text = b"head\n# >>> dotflex: zsh (features/zsh/aliases) >>>\nalias l=ls\n# <<< dotflex: zsh (features/zsh/aliases) <<<\ntail\n";
+        let (start, stop) = find_block(text, BEGIN, END).unwrap();
+        assert_eq!(&text[..start], b"head\n");
+        assert_eq!(&text[stop..], b"tail\n");
+    }
+
+    #[test]
+    fn ignores_trailing_whitespace_on_markers() {
+        let text = b"# >>> dotflex: zsh (features/zsh/aliases) >>> \r\nx\n# <<< dotflex: zsh (features/zsh/aliases) <<<";
+        assert_eq!(find_block(text, BEGIN, END), Some((0, text.len())));
+    }
+
+    #[test]
+    fn needs_both_markers() {
+        let text = b"# >>> dotflex: zsh (features/zsh/aliases) >>>\nalias l=ls\n";
+        assert_eq!(find_block(text, BEGIN, END), None);
+    }
+
+    #[test]
+    fn finds_content_at_the_end() {
+        assert_eq!(find_tail(b"head\nexport A=1\n", b"export A=1\n"), Some((5, 16)));
+        assert_eq!(find_tail(b"head\nexport A=1", b"export A=1\n"), Some((5, 15)));
+        assert_eq!(find_tail(b"export A=1\n", b"export A=1\n"), Some((0, 11)));
+    }
+
+    #[test]
+    fn leaves_content_elsewhere_alone() {
+        assert_eq!(find_tail(b"export A=1\ntail\n", b"export A=1\n"), None);
+        assert_eq!(find_tail(b"xexport A=1\n", b"export A=1\n"), None);
+        assert_eq!(find_tail(b"head\n", b""), None);
+    }
 }
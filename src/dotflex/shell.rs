@@ -0,0 +1,231 @@
+// -*- rust -*-
+// mod dotflex::shell
+//
+// Runs the commands behind OperationSchema::ShellString and ::ShellFile.
+// Their stdout and stderr are captured line by line: streamed to the
+// terminal in verbose mode, kept in the ShellResult so they can be shown
+// if the command fails, and appended to a log for the whole run at
+// LOCAL/logs/<unix-secs>-<pid>.log.
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::PathBuf;
+use std::os::unix::process::CommandExt;
+use std::process::{self, Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::common::output_verbose;
+use super::error::{DotflexError, Result};
+use super::util;
+
+// how many lines of output a failure shows; the rest is in the log
+const FAILURE_TAIL: usize = 20;
+// how often a running command is checked on
+const POLL: Duration = Duration::from_millis(50);
+// how long to keep reading output after the command has exited
+const DRAIN: Duration = Duration::from_secs(1);
+
+static RUN_STAMP: AtomicU64 = AtomicU64::new(0);
+
+/// How a shell command ended, and what it printed.
+#[derive(Debug)]
+pub struct ShellResult {
+    pub cmd: String,
+    /// the exit code, or None if the command was killed
+    pub status: Option<i32>,
+    /// the timeout the command was killed for exceeding, if it was
+    pub timed_out: Option<Duration>,
+    /// stdout and stderr, interleaved in the order they were read
+    pub output: Vec<String>,
+    pub log: PathBuf,
+}
+
+impl ShellResult {
+    pub fn success(&self) -> bool {
+        self.timed_out.is_none() && self.status == Some(0)
+    }
+}
+
+impl fmt::Display for ShellResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.timed_out, self.status) {
+            (Some(timeout), _) =>
+                write!(f, "{} timed out after {}s", self.cmd, timeout.as_secs())?,
+            (None, Some(code)) =>
+                write!(f, "{} exited with status {}", self.cmd, code)?,
+            (None, None) =>
+                write!(f, "{} was killed by a signal", self.cmd)?,
+        }
+        let skip = self.output.len().saturating_sub(FAILURE_TAIL);
+        if skip > 0 {
+            write!(f, "\n  | ...")?;
+        }
+        for line in self.output.iter().skip(skip) {
+            write!(f, "\n  | {}", line)?;
+        }
+        write!(f, "\n  (output logged to {})", self.log.display())
+    }
+}
+
+/// The log shell output from this run of dotflex goes to.
+pub fn run_log() -> PathBuf {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let stamp = match RUN_STAMP.compare_exchange(0, now, Ordering::SeqCst, Ordering::SeqCst) {
+        Ok(_) => now,
+        Err(stamp) => stamp,
+    };
+    util::local_path("logs").join(format!("{}-{}.log", stamp, process::id()))
+}
+
+/// Runs `command`, killing it if it takes longer than `timeout`. Only
+/// failing to start the command or to write the log is an error; check
+/// ShellResult::success for how the command itself went.
+pub fn run(command: &mut Command, description: &str, timeout: Option<Duration>) -> Result<ShellResult> {
+    let mut capture = Capture::open(run_log())?;
+    capture.note(&format!("== {}", description))?;
+
+    // in a process group of its own, so that a timeout kills whatever it
+    // started as well
+    let mut child = command
+        .process_group(0)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| DotflexError::io(command.get_program(), e))?;
+
+    let (sender, lines) = mpsc::channel();
+    forward_lines(child.stdout.take(), "out", sender.clone());
+    forward_lines(child.stderr.take(), "err", sender);
+
+    let started = Instant::now();
+    let mut timed_out = None;
+    let status = loop {
+        match lines.recv_timeout(POLL) {
+            // checked on after every line, so steady output can't hold
+            // off the timeout
+            Ok((stream, line)) => capture.line(stream, line)?,
+            Err(mpsc::RecvTimeoutError::Disconnected) => thread::sleep(POLL),
+            Err(mpsc::RecvTimeoutError::Timeout) => (),
+        }
+        if let Some(status) = child.try_wait().map_err(|e| capture.io_error(e))? {
+            break status;
+        }
+        if let Some(timeout) = timeout {
+            if timed_out.is_none() && started.elapsed() >= timeout {
+                // SAFETY: kill(2) only takes plain integers; the group is
+                // the child's, as it was spawned with process_group(0)
+                unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) };
+                timed_out = Some(timeout);
+            }
+        }
+    };
+    // pick up what's left in the pipes, without waiting long on background
+    // processes the command may have left holding them open
+    let deadline = Instant::now() + DRAIN;
+    while let Ok((stream, line)) = lines.recv_timeout(POLL) {
+        capture.line(stream, line)?;
+        if Instant::now() >= deadline {
+            capture.note("== stopped reading output still coming from background processes")?;
+            break;
+        }
+    }
+
+    match (timed_out, status.code()) {
+        (Some(timeout), _) => capture.note(&format!("== timed out after {}s", timeout.as_secs())),
+        (None, Some(code)) => capture.note(&format!("== exit status {}", code)),
+        (None, None) => capture.note("== killed by a signal"),
+    }?;
+    Ok(ShellResult {
+        cmd: description.to_string(),
+        status: status.code(),
+        timed_out,
+        output: capture.output,
+        log: capture.log_path,
+    })
+}
+
+/// Output collected from a running command.
+struct Capture {
+    log: File,
+    log_path: PathBuf,
+    output: Vec<String>,
+    streamed: bool,
+}
+
+impl Capture {
+    fn open(log_path: PathBuf) -> Result<Capture> {
+        util::assure_path_to(&log_path)?;
+        let log = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_path)
+            .map_err(|e| DotflexError::io(&log_path, e))?;
+        Ok(Capture { log, log_path, output: Vec::new(), streamed: false })
+    }
+
+    fn io_error(&self, e: io::Error) -> DotflexError {
+        DotflexError::io(&self.log_path, e)
+    }
+
+    fn note(&mut self, text: &str) -> Result<()> {
+        writeln!(self.log, "{}", text).map_err(|e| self.io_error(e))
+    }
+
+    fn line(&mut self, stream: &str, line: String) -> Result<()> {
+        self.note(&format!("{}| {}", stream, line))?;
+        if output_verbose() {
+            // the operation's description is still waiting for its "ok"
+            if !self.streamed {
+                println!();
+                self.streamed = true;
+            }
+            println!("    | {}", line);
+        }
+        self.output.push(line);
+        Ok(())
+    }
+}
+
+/// Sends each line read from `stream` to `sender`, tagged with `name`;
+/// bytes that aren't UTF-8 are replaced. The stream is read to the end
+/// whatever happens, since a command writing to a pipe that has been
+/// closed fails.
+fn forward_lines<R: Read + Send + 'static>(stream: Option<R>, name: &'static str,
+        sender: mpsc::Sender<(&'static str, String)>) {
+    thread::spawn(move || {
+        let mut reader = match stream {
+            Some(stream) => BufReader::new(stream),
+            None => return,
+        };
+        let mut listening = true;
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            match reader.read_until(b'\n', &mut line) {
+                Ok(0) => return,
+                Ok(_) if listening => {
+                    if line.ends_with(b"\n") {
+                        line.pop();
+                        if line.ends_with(b"\r") {
+                            line.pop();
+                        }
+                    }
+                    let text = String::from_utf8_lossy(&line).into_owned();
+                    listening = sender.send((name, text)).is_ok();
+                },
+                Ok(_) => (),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                // keep on draining, but don't spin on an error that persists
+                Err(_) => thread::sleep(POLL),
+            }
+        }
+    });
+}
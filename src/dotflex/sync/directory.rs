@@ -0,0 +1,171 @@
+// -*- rust -*-
+// mod dotflex::sync::directory
+//
+// Syncs the local repo with a plain directory, such as one on a mounted
+// drive, for machines that can't reach a git remote. Upsync makes the
+// directory an exact copy of the local repo and downsync does the
+// reverse; `.git` directories are left alone on both sides.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::dotflex::util;
+use crate::dotflex::error::{DotflexError, Result};
+use super::{SyncBackend, UpsyncOptions};
+
+pub struct Directory {
+    path: PathBuf,
+}
+
+impl Directory {
+    pub fn new<T: AsRef<Path>>(path: T) -> Directory {
+        Directory { path: path.as_ref().to_path_buf() }
+    }
+
+    /// Fails if the directory isn't there, e.g. because the drive it is on
+    /// isn't mounted.
+    fn check_present(&self) -> Result<()> {
+        if self.path.is_dir() {
+            Ok(())
+        } else {
+            Err(DotflexError::io(&self.path, io::Error::from(io::ErrorKind::NotFound)))
+        }
+    }
+}
+
+impl SyncBackend for Directory {
+    fn init(&self) -> Result<()> {
+        util::assure_path(util::repo_dir())?;
+        util::assure_path(&self.path)?;
+        // adopt what's already in the directory when starting afresh
+        if relative_files(&util::repo_dir())?.is_empty() {
+            mirror(&self.path, &util::repo_dir())?;
+        }
+        Ok(())
+    }
+
+    fn upsync(&self, _opts: &UpsyncOptions) -> Result<()> {
+        self.check_present()?;
+        let changed = mirror(&util::repo_dir(), &self.path)?;
+        println!("{} file(s) copied to {}", changed.len(), self.path.display());
+        Ok(())
+    }
+
+    fn downsync(&self) -> Result<Vec<PathBuf>> {
+        self.check_present()?;
+        let repo = util::repo_dir();
+        let there = relative_files(&self.path)?;
+        let removed = relative_files(&repo)?
+            .into_iter()
+            .filter(|file| !there.contains(file))
+            .collect::<Vec<_>>();
+        if !removed.is_empty() {
+            println!("removing {} file(s) that aren't in {}:", removed.len(), self.path.display());
+            for file in removed.iter() {
+                println!("  {}", file.display());
+            }
+        }
+        let changed = mirror(&self.path, &util::repo_dir())?;
+        println!("{} file(s) copied from {}", changed.len(), self.path.display());
+        Ok(changed)
+    }
+
+    fn status(&self) -> Result<String> {
+        let mut status = format!("directory mirror at {}", self.path.display());
+        if !self.path.is_dir() {
+            status.push_str("\n  (not present)");
+            return Ok(status);
+        }
+        let repo = util::repo_dir();
+        let here = relative_files(&repo)?;
+        let there = relative_files(&self.path)?;
+        for file in here.union(&there) {
+            let mark = match (here.contains(file), there.contains(file)) {
+                (true, false) => "+",
+                (false, true) => "-",
+                _ if util::same_contents(repo.join(file), self.path.join(file)) => continue,
+                _ => "M",
+            };
+            status.push_str(&format!("\n  {} {}", mark, file.display()));
+        }
+        Ok(status)
+    }
+}
+
+/// Makes `to` an exact copy of `from`; returns the paths, relative to
+/// both, that were copied or removed.
+fn mirror(from: &Path, to: &Path) -> Result<Vec<PathBuf>> {
+    util::assure_path(to)?;
+    let mut changed = Vec::new();
+    for entry in read_dir(to)? {
+        let name = entry.file_name();
+        if name == ".git" || fs::symlink_metadata(from.join(&name)).is_ok() {
+            continue;
+        }
+        util::remove_path(entry.path())?;
+        changed.push(PathBuf::from(name));
+    }
+    for entry in read_dir(from)? {
+        let name = entry.file_name();
+        if name == ".git" {
+            continue;
+        }
+        let (src, dest) = (entry.path(), to.join(&name));
+        let meta = fs::symlink_metadata(&src)
+            .map_err(|e| DotflexError::io(&src, e))?;
+        if meta.is_dir() {
+            if (dest.exists() && !dest.is_dir()) || util::is_symlink(&dest) {
+                util::remove_path(&dest)?;
+            }
+            changed.extend(mirror(&src, &dest)?
+                .into_iter()
+                .map(|path| Path::new(&name).join(path)));
+        } else if meta.file_type().is_symlink() {
+            let pointee = fs::read_link(&src)
+                .map_err(|e| DotflexError::io(&src, e))?;
+            if fs::read_link(&dest).ok().as_ref() != Some(&pointee) {
+                util::remove_path(&dest)?;
+                std::os::unix::fs::symlink(&pointee, &dest)
+                    .map_err(|e| DotflexError::io(&dest, e))?;
+                changed.push(PathBuf::from(name));
+            }
+        } else if !util::same_contents(&src, &dest) || util::is_symlink(&dest) {
+            util::remove_path(&dest)?;
+            fs::copy(&src, &dest)
+                .map_err(|e| DotflexError::io(&dest, e))?;
+            changed.push(PathBuf::from(name));
+        }
+    }
+    Ok(changed)
+}
+
+fn read_dir(dir: &Path) -> Result<Vec<fs::DirEntry>> {
+    fs::read_dir(dir)
+        .and_then(|rd| rd.collect::<io::Result<Vec<_>>>())
+        .map_err(|e| DotflexError::io(dir, e))
+}
+
+/// Every file under `dir`, relative to it, outside of `.git`.
+fn relative_files(dir: &Path) -> Result<BTreeSet<PathBuf>> {
+    fn walk(root: &Path, dir: &Path, files: &mut BTreeSet<PathBuf>) -> Result<()> {
+        for entry in read_dir(dir)? {
+            if entry.file_name() == ".git" {
+                continue;
+            }
+            let path = entry.path();
+            if path.is_dir() && !util::is_symlink(&path) {
+                walk(root, &path, files)?;
+            } else {
+                files.insert(path.strip_prefix(root).unwrap().to_path_buf());
+            }
+        }
+        Ok(())
+    }
+    let mut files = BTreeSet::new();
+    if dir.is_dir() {
+        walk(dir, dir, &mut files)?;
+    }
+    Ok(files)
+}
@@ -1,78 +1,277 @@
-use std::process::Command;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::process::{Command, Output};
 use std::io::{self, Write};
 use std::path::{Path, PathBuf};
 use crate::dotflex::util;
-use crate::dotflex::tracker::{TrackedFeature};
-use crate::dotflex::operation::{OperationInstance};
-use std::error::Error;
+use crate::dotflex::config;
+use crate::dotflex::error::{DotflexError, Result};
+use super::{SyncBackend, UpsyncOptions};
 
-pub fn upsync(features: Option<&Vec<TrackedFeature>>) -> bool {
-    // TODO: copy config dir files back to the repo
-    // may need to revise the schema to do this
+/// Syncs the local repo as a git repository, pushing to and pulling from
+/// `branch` of the remote `remote`.
+pub struct Git {
+    url: String,
+    remote: String,
+    branch: String,
+}
+
+impl Git {
+    pub fn new(url: &str, remote: &str, branch: &str) -> Git {
+        Git {
+            url: url.to_string(),
+            remote: remote.to_string(),
+            branch: branch.to_string(),
+        }
+    }
+
+    /// The git repository already set up in the local repo.
+    pub fn existing() -> Git {
+        let branch = current_branch().unwrap_or_else(config::default_branch);
+        let remote = query(&["config", &format!("branch.{}.remote", branch)])
+            .unwrap_or_else(config::default_remote);
+        let url = query(&["remote", "get-url", &remote]).unwrap_or_default();
+        Git { url, remote, branch }
+    }
+}
+
+/// The remote name and branch of the clone already in the local repo, if
+/// there is one: the remote is the one with the URL `url`, and the branch
+/// is the one checked out.
+pub fn detect(url: &str) -> (Option<String>, Option<String>) {
+    if !util::repo_path(".git").exists() {
+        return (None, None);
+    }
+    let remote = query(&["remote"])
+        .unwrap_or_default()
+        .lines()
+        .find(|name| query(&["remote", "get-url", name]).as_deref() == Some(url))
+        .map(String::from);
+    (remote, current_branch())
+}
 
-    let repo = util::repo_dir();
+fn current_branch() -> Option<String> {
+    query(&["symbolic-ref", "--short", "HEAD"])
+}
+
+/// Runs git in the local repo, failing if it can't be run or exits
+/// unsuccessfully.
+fn git(args: &[&str]) -> Result<Output> {
+    let output = Command::new("git")
+        .args(args)
+        .current_dir(util::repo_dir())
+        .output()
+        .map_err(|e| DotflexError::git(args, e.to_string()))?;
+    if !output.status.success() {
+        return Err(DotflexError::git(args,
+            String::from_utf8_lossy(&output.stderr).trim().to_string()));
+    }
+    Ok(output)
+}
+
+/// What git prints for `args`, trimmed, if it succeeds and prints anything.
+fn query(args: &[&str]) -> Option<String> {
+    git(args).ok()
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .filter(|out| !out.is_empty())
+}
 
-    let git_add = Command::new("git")
-        .args(&["add", "-A"])
-        .current_dir(repo.as_path())
-        .output();
+impl SyncBackend for Git {
+    fn init(&self) -> Result<()> {
+        util::assure_path_to(util::repo_dir())?;
+        let repo_dir = util::repo_dir();
+        if !repo_dir.join(".git").exists() {
+            let init_args = ["init", "-b", self.branch.as_str()];
+            let git_init = Command::new("git")
+                .args(init_args)
+                .arg(&repo_dir)
+                .status()
+                .map_err(|e| DotflexError::git(&init_args, e.to_string()))?;
+            if !git_init.success() {
+                return Err(DotflexError::git(&init_args,
+                    format!("could not initialize git repository at {}", repo_dir.display())));
+            }
+        }
+        // an adopted clone may have the remote already
+        if query(&["remote", "get-url", &self.remote]).as_deref() == Some(self.url.as_str()) {
+            return Ok(());
+        }
+        let remote_args = ["remote", "add", self.remote.as_str(), self.url.as_str()];
+        let git_remote = Command::new("git")
+            .args(remote_args)
+            .current_dir(repo_dir.as_path())
+            .status()
+            .map_err(|e| DotflexError::git(&remote_args, e.to_string()))?;
+        if !git_remote.success() {
+            return Err(DotflexError::git(&remote_args,
+                format!("could not set up remote {} for git repository at {}", self.url, repo_dir.display())));
+        }
+        Ok(())
+    }
 
-    if git_add.is_err() {
-        return false
+    fn keeps_history(&self) -> bool {
+        true
     }
 
-    if let Err(e) = io::stdout().write_all(&git_add.unwrap().stdout) {
-        panic!("error forwarding command output: {}", e);
+    fn commit(&self, opts: &UpsyncOptions) -> Result<()> {
+        let git_add = git(&["add", "-A"])?;
+        let _ = io::stdout().write_all(&git_add.stdout);
+
+        let staged = git(&["diff", "--cached", "--name-status", "--no-renames", "-z"])?;
+        let staged = String::from_utf8_lossy(&staged.stdout).into_owned();
+        if staged.trim().is_empty() {
+            println!("nothing to commit");
+        } else {
+            let summary = match &opts.message {
+                Some(message) => message.clone(),
+                None => summarize(&staged),
+            };
+            let message = format!("{}\n\nUpsynced from {}.", summary, util::hostname());
+            let mut commit_args = vec!["commit", "-m", message.as_str()];
+            if opts.edit {
+                commit_args.push("-e");
+            }
+            // run in the terminal, so that the editor can be used
+            let git_commit = Command::new("git")
+                .args(&commit_args)
+                .current_dir(util::repo_dir())
+                .status()
+                .map_err(|e| DotflexError::git(&["commit"], e.to_string()))?;
+            if !git_commit.success() {
+                return Err(DotflexError::git(&["commit"],
+                    format!("commit failed with {}", git_commit)));
+            }
+        }
+        Ok(())
     }
 
-    // leaving this as an expect for now
-    // since this should spawn vim or another editor
-    // EDIT: manual message
-    let git_commit = Command::new("git")
-        .args(&["commit", "-m", "upsync"])
-        .current_dir(repo.as_path())
-        .status()
-        .expect("Commit error");
+    fn upsync(&self, opts: &UpsyncOptions) -> Result<()> {
+        self.commit(opts)?;
+        let git_push = git(&["push", "-u", &self.remote, &self.branch])?;
+        let _ = io::stdout().write_all(&git_push.stdout);
 
-    let git_push = Command::new("git")
-        .args(&["push", "-u", "upstream", "master"])
-        .current_dir(repo.as_path())
-        .output();
+        Ok(())
+    }
 
-    if git_push.is_err() {
-        return false
+    fn downsync(&self) -> Result<Vec<PathBuf>> {
+        let before = query(&["rev-parse", "--verify", "HEAD"]);
+        let git_pull = git(&["pull", &self.remote, &self.branch])?;
+        let _ = io::stdout().write_all(&git_pull.stdout);
+        // everything is new to a repo that had no commits
+        let changed = match &before {
+            Some(before) => git(&["diff", "--name-only", "-z", before.as_str(), "HEAD"])?,
+            None => git(&["ls-files", "-z"])?,
+        };
+        // NUL-separated, so that paths come through unquoted and as bytes
+        Ok(changed.stdout
+            .split(|&b| b == 0)
+            .filter(|path| !path.is_empty())
+            .map(|path| PathBuf::from(OsStr::from_bytes(path)))
+            .collect())
     }
 
-    if let Err(e) = io::stdout().write_all(&git_push.unwrap().stdout) {
-        panic!("error forwarding command output: {}", e);
+    fn status(&self) -> Result<String> {
+        let git_status = git(&["status", "--short", "--branch"])?;
+        let mut status = format!("git, syncing {} with {} ({})", self.branch, self.remote, self.url);
+        for line in String::from_utf8_lossy(&git_status.stdout).lines() {
+            status.push_str(&format!("\n  {}", line));
+        }
+        Ok(status)
     }
+}
 
-    true
+/// Describes staged changes, given as `git diff --name-status -z` output,
+/// by feature: "zsh: update .zshrc; git: bind .gitconfig". Changes outside
+/// of the feature directories are listed last, with no feature name.
+fn summarize(name_status: &str) -> String {
+    // None for changes outside of the feature directories
+    let mut features: Vec<(Option<String>, Vec<String>)> = Vec::new();
+    let mut fields = name_status.split('\0');
+    while let (Some(status), Some(path)) = (fields.next(), fields.next()) {
+        let path = Path::new(path);
+        let (feature, file) = match path.strip_prefix("features") {
+            Ok(rest) if rest.components().count() > 1 => {
+                let mut components = rest.components();
+                let feature = components.next().unwrap()
+                    .as_os_str().to_string_lossy().into_owned();
+                (Some(feature), components.as_path().to_path_buf())
+            },
+            _ => (None, path.to_path_buf()),
+        };
+        let verb = match status.chars().next() {
+            Some('A') => "bind",
+            Some('D') => "unbind",
+            _ => "update",
+        };
+        let changes = match features.iter_mut().find(|(name, _)| *name == feature) {
+            Some((_, changes)) => changes,
+            None => {
+                features.push((feature, Vec::new()));
+                &mut features.last_mut().unwrap().1
+            },
+        };
+        // the manifest changes along with every bind and unbind, so it's
+        // only worth a mention on its own
+        if file != Path::new("manifest.yml") {
+            changes.push(format!("{} {}", verb, file.display()));
+        }
+    }
+    for (_, changes) in features.iter_mut() {
+        if changes.is_empty() {
+            changes.push(String::from("update manifest"));
+        }
+    }
+    // keep the rest last; features in order of name
+    features.sort_by(|(a, _), (b, _)| (a.is_none(), a).cmp(&(b.is_none(), b)));
+    let summary = features.iter()
+        .map(|(feature, changes)| match feature {
+            Some(feature) => format!("{}: {}", feature, changes.join(", ")),
+            None => changes.join(", "),
+        })
+        .collect::<Vec<_>>()
+        .join("; ");
+    if summary.is_empty() {
+        String::from("upsync")
+    } else {
+        summary
+    }
 }
 
-pub fn downsync(features: Option<&Vec<TrackedFeature>>) -> bool {
-    let repo = util::repo_path("");
+#[cfg(test)]
+mod tests {
+    use super::summarize;
 
-    let git_pull = Command::new("git")
-        .args(&["pull", "upstream", "master"])
-        .current_dir(&repo)
-        .output();
+    #[test]
+    fn groups_changes_by_feature() {
+        let staged = "M\0features/zsh/.zshrc\0A\0features/git/.gitconfig\0M\0features/git/manifest.yml\0";
+        assert_eq!(summarize(staged), "git: bind .gitconfig; zsh: update .zshrc");
+    }
 
-    if git_pull.is_err() {
-        return false;
+    #[test]
+    fn mentions_manifest_only_changes() {
+        assert_eq!(summarize("M\0features/zsh/manifest.yml\0"), "zsh: update manifest");
     }
 
-    if let Err(e) = io::stdout().write_all(&git_pull.unwrap().stdout) {
-        panic!("error forwarding command output: {}", e);
+    #[test]
+    fn lists_changes_outside_features_last() {
+        let staged = "M\0README.md\0D\0features/vim/.vimrc\0";
+        assert_eq!(summarize(staged), "vim: unbind .vimrc; update README.md");
     }
 
-    if features.is_some() {
-        let features = features.unwrap();
-        for feature in features.iter() {
-            if !feature.schema().install_feature() {
-                return false;
-            }
-        }
+    #[test]
+    fn keeps_a_feature_named_other_apart() {
+        let staged = "M\0notes.txt\0M\0features/other/.otherrc\0";
+        assert_eq!(summarize(staged), "other: update .otherrc; update notes.txt");
+    }
+
+    #[test]
+    fn takes_paths_with_tabs_and_newlines_whole() {
+        let staged = "A\0features/zsh/odd\tname\nhere\0";
+        assert_eq!(summarize(staged), "zsh: bind odd\tname\nhere");
+    }
+
+    #[test]
+    fn falls_back_when_nothing_is_staged() {
+        assert_eq!(summarize(""), "upsync");
     }
-    true
 }
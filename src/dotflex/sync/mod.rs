@@ -1 +1,65 @@
-pub mod git;
\ No newline at end of file
+// -*- rust -*-
+// mod dotflex::sync
+//
+// Moving the local repo to and from wherever the dotfiles are kept between
+// machines. The backend is chosen by `dotflex init` and stored in
+// LOCAL/config.yml; see config::SyncConfig.
+
+pub mod git;
+pub mod directory;
+
+use std::path::PathBuf;
+
+use super::config::{Config, SyncConfig};
+use super::error::{DotflexError, Result};
+use super::util;
+
+/// How to upsync; the message is for backends that keep a history.
+#[derive(Default)]
+pub struct UpsyncOptions {
+    /// use this message rather than generating one
+    pub message: Option<String>,
+    /// open the message in $EDITOR before committing
+    pub edit: bool,
+    /// ask before collecting each edited target file into the local repo
+    pub confirm: bool,
+}
+
+pub trait SyncBackend {
+    /// Sets up the local repo to sync with this backend.
+    fn init(&self) -> Result<()>;
+    /// Whether the backend keeps a history that commit adds to.
+    fn keeps_history(&self) -> bool {
+        false
+    }
+    /// Records the local repo as it is without sending it, for backends
+    /// that keep a history.
+    fn commit(&self, _opts: &UpsyncOptions) -> Result<()> {
+        Ok(())
+    }
+    /// Sends the contents of the local repo.
+    fn upsync(&self, opts: &UpsyncOptions) -> Result<()>;
+    /// Brings the local repo up to date, returning the paths, relative to
+    /// the local repo, that changed.
+    fn downsync(&self) -> Result<Vec<PathBuf>>;
+    /// Describes the backend on its first line, then how the local repo
+    /// differs from what was last synced on indented lines after it.
+    fn status(&self) -> Result<String>;
+}
+
+pub fn backend(config: &SyncConfig) -> Box<dyn SyncBackend> {
+    match config {
+        SyncConfig::Git { url, remote, branch } => Box::new(git::Git::new(url, remote, branch)),
+        SyncConfig::Directory { path } => Box::new(directory::Directory::new(path)),
+    }
+}
+
+/// The backend chosen with `dotflex init`. Local repos set up before the
+/// choice was recorded are git repos.
+pub fn configured() -> Result<Box<dyn SyncBackend>> {
+    match Config::load()?.sync {
+        Some(sync) => Ok(backend(&sync)),
+        None if util::repo_path(".git").exists() => Ok(Box::new(git::Git::existing())),
+        None => Err(DotflexError::NoRepo(util::repo_dir())),
+    }
+}
@@ -0,0 +1,104 @@
+// -*- rust -*-
+// mod dotflex::template
+//
+// Rendering for OperationSchema::Template. Placeholders look like
+// `{{ name }}`; values come from, in increasing order of precedence:
+//   - the host: hostname, os, arch, user, home
+//   - the `vars` of the operation in the feature manifest
+//   - LOCAL/vars.yml, a flat map of per-machine values
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::Path;
+
+use super::util;
+use super::error::{DotflexError, Result};
+
+/// Variables describing the machine dotflex is running on.
+pub fn host_vars() -> BTreeMap<String, String> {
+    let mut vars = BTreeMap::new();
+    vars.insert(String::from("hostname"), util::hostname());
+    vars.insert(String::from("os"), String::from(env::consts::OS));
+    vars.insert(String::from("arch"), String::from(env::consts::ARCH));
+    vars.insert(String::from("user"), util::username());
+    vars.insert(String::from("home"), util::target_dir().display().to_string());
+    vars
+}
+
+/// User-defined variables for this machine, from LOCAL/vars.yml.
+pub fn local_vars() -> Result<BTreeMap<String, String>> {
+    let path = util::local_path("vars.yml");
+    if !path.exists() {
+        return Ok(BTreeMap::new());
+    }
+    let contents = fs::read_to_string(&path)
+        .map_err(|e| DotflexError::io(&path, e))?;
+    serde_yaml::from_str(&contents)
+        .map_err(|e| DotflexError::yaml(&path, e))
+}
+
+/// Renders the template at `from` with the operation's `vars`.
+pub fn render_file<T: AsRef<Path>>(from: T, vars: &BTreeMap<String, String>) -> Result<String> {
+    let from = from.as_ref();
+    let contents = fs::read_to_string(from)
+        .map_err(|e| DotflexError::io(from, e))?;
+    let mut all_vars = host_vars();
+    all_vars.extend(vars.iter().map(|(k, v)| (k.clone(), v.clone())));
+    all_vars.extend(local_vars()?);
+    render(&contents, &all_vars)
+        .map_err(|detail| DotflexError::Template { path: from.to_path_buf(), detail })
+}
+
+/// Substitutes every `{{ name }}` in `template`; unknown names are an error
+/// rather than silently rendering as empty.
+pub fn render(template: &str, vars: &BTreeMap<String, String>) -> std::result::Result<String, String> {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after.find("}}")
+            .ok_or_else(|| String::from("unterminated '{{'"))?;
+        let name = after[..end].trim();
+        let value = vars.get(name)
+            .ok_or_else(|| format!("undefined variable '{}'", name))?;
+        out.push_str(value);
+        rest = &after[end + 2..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::render;
+    use std::collections::BTreeMap;
+
+    fn vars() -> BTreeMap<String, String> {
+        let mut vars = BTreeMap::new();
+        vars.insert(String::from("user"), String::from("max"));
+        vars.insert(String::from("os"), String::from("linux"));
+        vars
+    }
+
+    #[test]
+    fn substitutes_variables() {
+        assert_eq!(render("{{user}}@{{ os }}: {{  user }}", &vars()).unwrap(), "max@linux: max");
+    }
+
+    #[test]
+    fn leaves_plain_text_alone() {
+        assert_eq!(render("no {braces} here }}", &vars()).unwrap(), "no {braces} here }}");
+    }
+
+    #[test]
+    fn rejects_undefined_variables() {
+        assert_eq!(render("{{ shell }}", &vars()), Err(String::from("undefined variable 'shell'")));
+    }
+
+    #[test]
+    fn rejects_unterminated_placeholders() {
+        assert_eq!(render("{{ user", &vars()), Err(String::from("unterminated '{{'")));
+    }
+}
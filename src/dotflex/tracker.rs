@@ -1,21 +1,214 @@
 use std::collections::{hash_map::Entry, HashMap};
 use std::path::{Path, PathBuf};
 use super::operation::{FeatureSchema, OperationSchema};
-use std::fs::{self, OpenOptions, DirEntry};
+use std::fs::{self, OpenOptions};
 use std::io::{BufReader, BufWriter};
+use std::fmt;
+use std::os::unix::fs::PermissionsExt;
+use std::time::{SystemTime, UNIX_EPOCH};
 use serde::{Serialize, Deserialize};
 use super::util;
+use super::merge;
+use super::error::{DotflexError, Result};
 
 #[derive(Serialize, Deserialize)]
 pub struct InstalledFile {
     repo_path: PathBuf,
 
-    // Some: installed directly via OperationSchema::Copy
+    // Some: installed directly via OperationSchema::Copy or ::Link
     // None: installed through some other OperationSchema
     local_path: Option<PathBuf>,
+
+    // true: local_path is a symlink to repo_path rather than a copy
+    link: bool,
+
+    // what local_path looked like right after it was installed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    installed: Option<FileRecord>,
+}
+
+/// The state of an installed file at install time.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct FileRecord {
+    /// SHA-256 of the contents; for directories, of every file's relative
+    /// path and contents
+    sha256: String,
+    /// in bytes; for directories, of every file in them
+    size: u64,
+    /// permission bits
+    mode: u32,
+    /// seconds since the Unix epoch
+    installed_at: u64,
+}
+
+impl FileRecord {
+    /// Records the current state of `path`, or None if it can't be read.
+    pub fn of<T: AsRef<Path>>(path: T) -> Option<FileRecord> {
+        let path = path.as_ref();
+        let (sha256, size) = util::content_hash(path)?;
+        Some(FileRecord {
+            sha256,
+            size,
+            mode: fs::metadata(path).ok()?.permissions().mode() & 0o7777,
+            installed_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+        })
+    }
+
+    pub fn sha256(&self) -> &str {
+        &self.sha256
+    }
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+    pub fn mode(&self) -> u32 {
+        self.mode
+    }
+    pub fn installed_at(&self) -> u64 {
+        self.installed_at
+    }
+
+    /// Whether `path` still has the recorded contents.
+    pub fn matches<T: AsRef<Path>>(&self, path: T) -> bool {
+        util::content_hash(path)
+            .map_or(false, |(sha256, size)| size == self.size && sha256 == self.sha256)
+    }
+}
+
+/// State of a target path installed by `OperationSchema::LinkFile`.
+#[derive(Clone, Copy, PartialEq)]
+pub enum LinkState {
+    Linked,
+    Broken,
+    Replaced,
+    Elsewhere,
+    Missing,
+}
+
+impl fmt::Display for LinkState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinkState::Linked => write!(f, "linked"),
+            LinkState::Broken => write!(f, "broken link"),
+            LinkState::Replaced => write!(f, "replaced by a regular file"),
+            LinkState::Elsewhere => write!(f, "links elsewhere"),
+            LinkState::Missing => write!(f, "missing"),
+        }
+    }
+}
+
+/// How a bound file in the target directory compares to its repo copy.
+#[derive(Clone, Copy, PartialEq)]
+pub enum FileStatus {
+    InSync,
+    ModifiedInTarget,
+    ModifiedInRepo,
+    ModifiedInBoth,
+    MissingInTarget,
+    MissingInRepo,
+}
+
+impl fmt::Display for FileStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileStatus::InSync => write!(f, "in sync"),
+            FileStatus::ModifiedInTarget => write!(f, "modified in target"),
+            FileStatus::ModifiedInRepo => write!(f, "modified in repo"),
+            FileStatus::ModifiedInBoth => write!(f, "modified in both"),
+            FileStatus::MissingInTarget => write!(f, "missing in target"),
+            FileStatus::MissingInRepo => write!(f, "missing in repo"),
+        }
+    }
+}
+
+impl FileStatus {
+    pub fn drifted(&self) -> bool {
+        *self != FileStatus::InSync
+    }
 }
 
 impl InstalledFile {
+    /// Compares the target file with the repo file. When both exist but
+    /// differ, the one that no longer matches what was installed is the
+    /// edited one; without an install record, whichever was modified last
+    /// is taken to be.
+    pub fn status(&self) -> Option<FileStatus> {
+        let target = util::resolve_path_target(self.local_path.as_ref()?);
+        let repo = util::resolve_path_repo(&self.repo_path);
+        if let Some(state) = self.link_state() {
+            return Some(match state {
+                LinkState::Linked => FileStatus::InSync,
+                LinkState::Broken => FileStatus::MissingInRepo,
+                LinkState::Replaced | LinkState::Elsewhere => FileStatus::ModifiedInTarget,
+                LinkState::Missing => FileStatus::MissingInTarget,
+            });
+        }
+        Some(match (target.exists(), repo.exists()) {
+            (false, _) => FileStatus::MissingInTarget,
+            (true, false) => FileStatus::MissingInRepo,
+            (true, true) if util::same_contents(&target, &repo) => FileStatus::InSync,
+            (true, true) if self.installed.is_some() => {
+                let record = self.installed.as_ref().unwrap();
+                match (record.matches(&target), record.matches(&repo)) {
+                    (true, _) => FileStatus::ModifiedInRepo,
+                    (false, true) => FileStatus::ModifiedInTarget,
+                    (false, false) => FileStatus::ModifiedInBoth,
+                }
+            },
+            (true, true) => {
+                if util::newest_mtime(&target) >= util::newest_mtime(&repo) {
+                    FileStatus::ModifiedInTarget
+                } else {
+                    FileStatus::ModifiedInRepo
+                }
+            }
+        })
+    }
+
+    pub fn is_link(&self) -> bool {
+        self.link
+    }
+
+    /// For linked files, how the target currently relates to the repo file.
+    pub fn link_state(&self) -> Option<LinkState> {
+        if !self.link {
+            return None;
+        }
+        let target = util::resolve_path_target(self.local_path.as_ref()?);
+        let repo = util::resolve_path_repo(&self.repo_path);
+        Some(if util::is_symlink(&target) {
+            if !target.exists() {
+                LinkState::Broken
+            } else if util::links_to(&target, &repo) {
+                LinkState::Linked
+            } else {
+                LinkState::Elsewhere
+            }
+        } else if target.exists() {
+            LinkState::Replaced
+        } else {
+            LinkState::Missing
+        })
+    }
+
+    pub fn installed(&self) -> Option<&FileRecord> {
+        self.installed.as_ref()
+    }
+
+    /// Records the target as it is now, as the installed state.
+    pub fn record_installed(&mut self) {
+        self.installed = self.local_path.as_ref()
+            .and_then(|path| FileRecord::of(util::resolve_path_target(path)));
+    }
+    pub fn set_installed(&mut self, record: Option<FileRecord>) {
+        self.installed = record;
+    }
+    pub fn forget_installed(&mut self) {
+        self.installed = None;
+    }
+
     pub fn repo_path(&self) -> &Path {
         self.repo_path.as_path()
     }
@@ -31,10 +224,17 @@ impl InstalledFile {
 pub struct TrackedFeature {
     name: String,
     active: bool,
-    #[serde(skip)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     files: Vec<InstalledFile>,
     #[serde(skip, default = "FeatureSchema::new")]
     schema: FeatureSchema,
+    // whether the schema's `when` condition holds on this host
+    #[serde(skip, default = "applicable_default")]
+    applicable: bool,
+}
+
+fn applicable_default() -> bool {
+    true
 }
 
 impl TrackedFeature {
@@ -56,26 +256,67 @@ impl TrackedFeature {
             name: name_,
             active: active_,
             files: Self::files_from_schema(&schema_),
+            applicable: schema_.applies(),
             schema: schema_
         }
     }
 
     pub fn files_from_schema(schema: &FeatureSchema) -> Vec<InstalledFile> {
         schema.install_operations().iter()
+            .filter(|op| op.applies())
             .filter_map(|op| {
                 match op {
-                    OperationSchema::CopyFile { from, to }
+                    OperationSchema::CopyFile { from, to, .. }
+                        => Some(InstalledFile {
+                            repo_path: PathBuf::from(from),
+                            local_path: Some(PathBuf::from(to)),
+                            link: false,
+                            installed: None,
+                        }),
+                    OperationSchema::LinkFile { from, to, .. }
                         => Some(InstalledFile {
                             repo_path: PathBuf::from(from),
                             local_path: Some(PathBuf::from(to)),
+                            link: true,
+                            installed: None,
                         }),
                     _ => None
                 }
             })
         .collect::<Vec<_>>()
     }
+    /// Recomputes the installed files from the schema, keeping the install
+    /// records of files that are still there.
     pub fn update_files_from_schema(&mut self) {
-        self.files = Self::files_from_schema(&self.schema);
+        let mut files = Self::files_from_schema(&self.schema);
+        for file in files.iter_mut() {
+            file.installed = self.files.iter()
+                .find(|old| old.repo_path == file.repo_path
+                    && old.local_path == file.local_path
+                    && old.link == file.link)
+                .and_then(|old| old.installed.clone());
+        }
+        self.files = files;
+    }
+
+    /// Records the installed file at `local_path`, as given in the schema,
+    /// as it is now in the target directory, and keeps a copy of it as the
+    /// base for later merges; see InstalledFile::record_installed and
+    /// merge::save_base.
+    pub fn record_installed_file<T: AsRef<Path>>(&mut self, local_path: T) -> Result<()> {
+        for file in self.files.iter_mut() {
+            if file.local_path() == Some(local_path.as_ref()) {
+                file.record_installed();
+                merge::save_base(&self.name, file)?;
+            }
+        }
+        Ok(())
+    }
+    pub fn forget_installed(&mut self) -> Result<()> {
+        for file in self.files.iter_mut() {
+            file.forget_installed();
+        }
+        merge::forget_bases(&self.name)
     }
 
     pub fn name(&self) -> &String {
@@ -86,6 +327,11 @@ impl TrackedFeature {
         self.active
     }
 
+    /// False if the feature's `when` condition doesn't hold on this host.
+    pub fn applicable(&self) -> bool {
+        self.applicable
+    }
+
     pub fn mark_active(&mut self, active: bool) {
         self.active = active;
     }
@@ -104,7 +350,9 @@ impl TrackedFeature {
         &mut self.schema
     }
     pub fn insert_schema(&mut self, schema: FeatureSchema) {
+        self.applicable = schema.applies();
         self.schema = schema;
+        self.update_files_from_schema();
     }
 }
 
@@ -136,73 +384,98 @@ impl Features {
         self._mark_active(name, false)
     }
 
-    pub fn load_local() -> Features {
-        let path = util::local_path("features.yml");
-        let mut features = Features {
-            features : HashMap::new()
-        };
-        if path.exists() {
-            let file = OpenOptions::new()
-                .create(false)
-                .read(true)
-                .open(&path);
-
-            if let Err(e) = file {
-                eprintln!("couldn't open features manifest {} for reading: {}",
-                    &path.display(),
-                    e);
-                std::process::exit(1);
+    /// Orders `names` and everything they (transitively) require so that
+    /// each feature comes after its dependencies. Missing features,
+    /// dependency cycles and required features that are not applicable on
+    /// this host are all reported at once.
+    pub fn install_order(&self, names: &[&str]) -> Result<Vec<String>> {
+        let mut order = Vec::new();
+        let mut problems = Vec::new();
+        let mut visiting = Vec::new();
+        for name in names.iter() {
+            if !self.features.contains_key(*name) {
+                problems.push(format!("no such feature: {}", name));
+                continue;
             }
+            self._visit(name, &mut visiting, &mut order, &mut problems);
+        }
+        if problems.is_empty() {
+            Ok(order)
+        } else {
+            Err(DotflexError::Dependencies(problems))
+        }
+    }
 
-            let reader = BufReader::new(file.unwrap());
-            let features_manifest = serde_yaml::from_reader(reader);
+    /// Active features that list `name` in their `requires`.
+    pub fn dependents(&self, name: &str) -> Vec<&str> {
+        let mut dependents = self.features.values()
+            .filter(|f| f.active())
+            .filter(|f| f.schema().requires().iter().any(|r| r == name))
+            .map(|f| f.name().as_str())
+            .collect::<Vec<_>>();
+        dependents.sort();
+        dependents
+    }
 
-            if let Err(e) = features_manifest {
-                eprintln!("couldn't parse features manifest {}: {}",
-                    &path.display(),
-                    e);
-                std::process::exit(1);
-            }
-            features = features_manifest.unwrap();
+    pub fn load_local() -> Result<Features> {
+        let path = util::local_path("features.yml");
+        if !path.exists() {
+            return Ok(Features {
+                features : HashMap::new()
+            });
         }
-
-        features
+        let file = OpenOptions::new()
+            .create(false)
+            .read(true)
+            .open(&path)
+            .map_err(|e| DotflexError::io(&path, e))?;
+        let reader = BufReader::new(file);
+        serde_yaml::from_reader(reader)
+            .map_err(|e| DotflexError::yaml(&path, e))
     }
 
-    pub fn dump_local(&self) {
+    pub fn dump_local(&self) -> Result<()> {
         let path = util::local_path("features.yml");
-        super::util::assure_path_to(&path);
+        super::util::assure_path_to(&path)?;
         let file = OpenOptions::new()
             .create(true)
             .write(true)
             .truncate(true)
-            .open(&path);
-        if let Err(e) = file {
-            eprintln!("couldn't open active features manifest {} for writing: {}",
-                &path.display(),
-                e);
-            std::process::exit(1);
-        }
-        let writer = BufWriter::new(file.unwrap());
-        let write = serde_yaml::to_writer(writer, self);
-        if let Err(e) = write {
-            eprintln!("couldn't write to active features manifest {}: {}",
-                &path.display(),
-                e);
-            std::process::exit(1);
-        }
-    }
-
-    pub fn install_all(&self) -> bool {
-        for (feature_name, feature) in &self.features {
-            if !feature.active() {
-                if !feature.schema.install_feature() {
-                    return false;
-                }
+            .open(&path)
+            .map_err(|e| DotflexError::io(&path, e))?;
+        let writer = BufWriter::new(file);
+        serde_yaml::to_writer(writer, self)
+            .map_err(|e| DotflexError::yaml(&path, e))
+    }
+
+    fn _visit(&self, name: &str, visiting: &mut Vec<String>,
+        order: &mut Vec<String>, problems: &mut Vec<String>) {
+        if order.iter().any(|n| n == name) {
+            return;
+        }
+        if let Some(pos) = visiting.iter().position(|n| n == name) {
+            let mut cycle = visiting[pos..].to_vec();
+            cycle.push(name.to_string());
+            problems.push(format!("dependency cycle: {}", cycle.join(" -> ")));
+            return;
+        }
+        let feature = &self.features[name];
+        visiting.push(name.to_string());
+        for dep in feature.schema().requires().iter() {
+            if !self.features.contains_key(dep) {
+                problems.push(format!("feature {} requires missing feature {}", name, dep));
+                continue;
+            }
+            let required = &self.features[dep];
+            if !required.active() && !required.applicable() {
+                problems.push(format!("feature {} requires feature {}, which is not applicable on this host",
+                    name, dep));
+                continue;
             }
+            self._visit(dep, visiting, order, problems);
         }
-
-        true
+        visiting.pop();
+        order.push(name.to_string());
     }
 
     fn _mark_active(&mut self, name: &str, val: bool) -> bool {
@@ -215,3 +488,76 @@ impl Features {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Features named `(name, active, manifest)`.
+    fn features(specs: &[(&str, bool, &str)]) -> Features {
+        let features = specs.iter()
+            .map(|(name, active, manifest)| {
+                let schema = serde_yaml::from_str(&format!("{}\ninstall: []\nuninstall: []\n", manifest))
+                    .unwrap();
+                (name.to_string(), TrackedFeature::new(name.to_string(), *active, schema))
+            })
+            .collect();
+        Features { features }
+    }
+
+    fn problems(result: Result<Vec<String>>) -> Vec<String> {
+        match result {
+            Err(DotflexError::Dependencies(problems)) => problems,
+            _ => panic!("expected dependency problems"),
+        }
+    }
+
+    #[test]
+    fn orders_requirements_first() {
+        let features = features(&[
+            ("base", false, "requires: []"),
+            ("shell", false, "requires: [base]"),
+            ("zsh", false, "requires: [shell, base]"),
+        ]);
+        assert_eq!(features.install_order(&["zsh"]).unwrap(), vec!["base", "shell", "zsh"]);
+        assert_eq!(features.install_order(&["shell", "zsh"]).unwrap(), vec!["base", "shell", "zsh"]);
+    }
+
+    #[test]
+    fn reports_cycles() {
+        let features = features(&[
+            ("a", false, "requires: [b]"),
+            ("b", false, "requires: [a]"),
+        ]);
+        assert_eq!(problems(features.install_order(&["a"])), vec!["dependency cycle: a -> b -> a"]);
+    }
+
+    #[test]
+    fn reports_missing_features() {
+        let features = features(&[("a", false, "requires: [gone]")]);
+        assert_eq!(problems(features.install_order(&["a", "nope"])), vec![
+            "feature a requires missing feature gone",
+            "no such feature: nope",
+        ]);
+    }
+
+    #[test]
+    fn reports_requirements_not_applicable_here() {
+        let features = features(&[
+            ("elsewhere", false, "when:\n  os: plan9"),
+            ("top", false, "requires: [elsewhere]"),
+        ]);
+        assert_eq!(problems(features.install_order(&["top"])), vec![
+            "feature top requires feature elsewhere, which is not applicable on this host",
+        ]);
+    }
+
+    #[test]
+    fn accepts_active_requirements_not_applicable_here() {
+        let features = features(&[
+            ("elsewhere", true, "when:\n  os: plan9"),
+            ("top", false, "requires: [elsewhere]"),
+        ]);
+        assert_eq!(features.install_order(&["top"]).unwrap(), vec!["elsewhere", "top"]);
+    }
+}
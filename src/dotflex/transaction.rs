@@ -0,0 +1,160 @@
+// -*- rust -*-
+// mod dotflex::transaction
+//
+// Runs a series of operations so that a failure part way through can be
+// rolled back. Before an operation runs, every path it is going to touch
+// (see OperationInstance::plan) is snapshotted into a scratch directory
+// under LOCAL/transactions/, or noted as absent; rolling back restores the
+// snapshots and removes paths that didn't exist, newest first.
+//
+// Shell operations can only be undone as far as their declared `effects`
+// go.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::common::output_verbose;
+use super::error::{DotflexError, Result};
+use super::operation::OperationInstance;
+use super::util;
+
+/// How to put a single path back the way it was.
+enum Undo {
+    /// the path didn't exist
+    Remove(PathBuf),
+    /// the path's previous contents were saved at `saved`
+    Restore { path: PathBuf, saved: PathBuf },
+}
+
+pub struct Transaction {
+    stamp: u64,
+    // made on the first snapshot, so that nothing is written until then
+    scratch: Option<PathBuf>,
+    guarded: Vec<PathBuf>,
+    undo: Vec<Undo>,
+}
+
+impl Transaction {
+    pub fn begin() -> Transaction {
+        let stamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        Transaction {
+            stamp,
+            scratch: None,
+            guarded: Vec::new(),
+            undo: Vec::new(),
+        }
+    }
+
+    /// The scratch directory, made the first time it is needed; another
+    /// transaction begun in the same second, in this process or another
+    /// one with the same pid, gets a directory of its own.
+    fn scratch(&mut self) -> Result<PathBuf> {
+        if let Some(scratch) = &self.scratch {
+            return Ok(scratch.clone());
+        }
+        let parent = transactions_dir();
+        util::assure_path(&parent)?;
+        let mut attempt = 0;
+        let scratch = loop {
+            let dir = parent.join(format!("{}-{}-{}", self.stamp, process::id(), attempt));
+            match fs::create_dir(&dir) {
+                Ok(()) => break dir,
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => attempt += 1,
+                Err(e) => return Err(DotflexError::io(&dir, e)),
+            }
+        };
+        self.scratch = Some(scratch.clone());
+        Ok(scratch)
+    }
+
+    /// Records the current state of `path` so that rollback can restore
+    /// it. Only the first call for a given path in a transaction counts.
+    pub fn guard<T: AsRef<Path>>(&mut self, path: T) -> Result<()> {
+        let path = path.as_ref();
+        if self.guarded.iter().any(|p| p == path) {
+            return Ok(());
+        }
+        if fs::symlink_metadata(path).is_err() {
+            self.undo.push(Undo::Remove(path.to_path_buf()));
+        } else {
+            let saved = self.scratch()?.join(self.undo.len().to_string());
+            util::assure_path_to(&saved)?;
+            copy_preserving(path, &saved)?;
+            self.undo.push(Undo::Restore { path: path.to_path_buf(), saved });
+        }
+        self.guarded.push(path.to_path_buf());
+        Ok(())
+    }
+
+    /// Runs an operation, guarding every path it is going to touch first.
+    pub fn execute(&mut self, inst: &OperationInstance) -> Result<()> {
+        for (path, _) in inst.plan() {
+            self.guard(&path)?;
+        }
+        inst.execute()
+    }
+
+    /// Keeps everything done in this transaction.
+    pub fn commit(self) -> Result<()> {
+        if let Some(scratch) = &self.scratch {
+            util::remove_path(scratch)?;
+            // only goes if no other transaction is under way
+            let _ = fs::remove_dir(transactions_dir());
+        }
+        Ok(())
+    }
+
+    /// Undoes everything done in this transaction, newest first. Keeps
+    /// going past paths that can't be restored, and reports the first one.
+    pub fn rollback(mut self) -> Result<()> {
+        let mut first_error = None;
+        while let Some(undo) = self.undo.pop() {
+            let result = match &undo {
+                Undo::Remove(path) => {
+                    if output_verbose() {
+                        println!("  rollback: removing {}", path.display());
+                    }
+                    util::remove_path(path)
+                },
+                Undo::Restore { path, saved } => {
+                    if output_verbose() {
+                        println!("  rollback: restoring {}", path.display());
+                    }
+                    util::remove_path(path)
+                        .and_then(|_| copy_preserving(saved, path))
+                },
+            };
+            if let Err(e) = result {
+                first_error.get_or_insert(e);
+            }
+        }
+        match first_error {
+            Some(e) => Err(e),
+            None => self.commit(),
+        }
+    }
+}
+
+fn transactions_dir() -> PathBuf {
+    util::local_path("transactions")
+}
+
+/// Copies a file, directory or symlink without following symlinks.
+fn copy_preserving(from: &Path, to: &Path) -> Result<()> {
+    let output = Command::new("cp").arg("-PR").arg(from).arg(to).output()
+        .map_err(|e| DotflexError::io(from, e))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(DotflexError::Command {
+            cmd: format!("cp -PR {} {}", from.display(), to.display()),
+            status: output.status.code(),
+        })
+    }
+}
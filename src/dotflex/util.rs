@@ -6,22 +6,203 @@
 use dirs_next;
 use std::env;
 use std::ffi::OsStr;
-use std::path::{Component, Path, PathBuf};
+use std::path::{Path, PathBuf};
 use std::fs;
+use std::process::Command;
+use std::time::SystemTime;
+use sha2::{Digest, Sha256};
+use super::error::{DotflexError, Result};
 
-pub fn assure_path<T: AsRef<Path>> (path: T) -> bool {
-    fs::create_dir_all(path).is_ok()
+pub fn assure_path<T: AsRef<Path>> (path: T) -> Result<()> {
+    fs::create_dir_all(&path)
+        .map_err(|e| DotflexError::io(&path, e))
 }
 
-pub fn assure_path_to<T: AsRef<Path>>(path: T) -> bool {
+pub fn assure_path_to<T: AsRef<Path>>(path: T) -> Result<()> {
     let parent_path_maybe = path.as_ref().parent();
     if let Some(path) = parent_path_maybe {
         assure_path(path)
     } else {
-        true
+        Ok(())
     }
 }
 
+/// Removes a file, symlink or directory tree; a path that doesn't exist is
+/// treated as already removed.
+pub fn remove_path<T: AsRef<Path>>(path: T) -> Result<()> {
+    let path = path.as_ref();
+    let removed = match fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(_) => Ok(()),
+    };
+    removed.map_err(|e| DotflexError::io(path, e))
+}
+
+pub fn is_symlink<T: AsRef<Path>>(path: T) -> bool {
+    fs::symlink_metadata(path)
+        .map(|meta| meta.file_type().is_symlink())
+        .unwrap_or(false)
+}
+
+/// Whether `link` is a symlink pointing at `dest`.
+pub fn links_to<T: AsRef<Path>, U: AsRef<Path>>(link: T, dest: U) -> bool {
+    match fs::read_link(&link) {
+        Ok(pointee) => {
+            let pointee = match link.as_ref().parent() {
+                Some(parent) => parent.join(pointee),
+                None => pointee,
+            };
+            pointee == dest.as_ref()
+                || fs::canonicalize(&pointee).ok() == fs::canonicalize(&dest).ok()
+        },
+        Err(_) => false,
+    }
+}
+
+/// Whether two files or directory trees have identical contents.
+pub fn same_contents<T: AsRef<Path>, U: AsRef<Path>>(a: T, b: U) -> bool {
+    let (a, b) = (a.as_ref(), b.as_ref());
+    if a.is_dir() && b.is_dir() {
+        let names = |dir: &Path| -> Option<Vec<_>> {
+            let mut names = fs::read_dir(dir).ok()?
+                .filter_map(|ent| ent.ok())
+                .map(|ent| ent.file_name())
+                .collect::<Vec<_>>();
+            names.sort();
+            Some(names)
+        };
+        match (names(a), names(b)) {
+            (Some(na), Some(nb)) => na == nb
+                && na.iter().all(|n| same_contents(a.join(n), b.join(n))),
+            _ => false,
+        }
+    } else if a.is_file() && b.is_file() {
+        match (fs::read(a), fs::read(b)) {
+            (Ok(ca), Ok(cb)) => ca == cb,
+            _ => false,
+        }
+    } else {
+        false
+    }
+}
+
+/// SHA-256 (in hex) and size of a file's contents. For a directory tree,
+/// the hash covers each file's path relative to `path` and its contents,
+/// in sorted order, and the size is the total of the files.
+pub fn content_hash<T: AsRef<Path>>(path: T) -> Option<(String, u64)> {
+    fn feed(hasher: &mut Sha256, root: &Path, path: &Path) -> Option<u64> {
+        if path.is_dir() {
+            let mut entries = fs::read_dir(path).ok()?
+                .filter_map(|ent| ent.ok())
+                .map(|ent| ent.path())
+                .collect::<Vec<_>>();
+            entries.sort();
+            let mut size = 0;
+            for entry in entries.iter() {
+                size += feed(hasher, root, entry)?;
+            }
+            Some(size)
+        } else {
+            let contents = fs::read(path).ok()?;
+            if path != root {
+                let relative = path.strip_prefix(root).ok()?;
+                hasher.update(relative.to_string_lossy().as_bytes());
+                hasher.update(&[0]);
+            }
+            hasher.update(&contents);
+            Some(contents.len() as u64)
+        }
+    }
+    let path = path.as_ref();
+    let mut hasher = Sha256::new();
+    let size = feed(&mut hasher, path, path)?;
+    let hash = hasher.finalize().iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    Some((hash, size))
+}
+
+/// Last modification time of a file, or of the newest file in a directory.
+pub fn newest_mtime<T: AsRef<Path>>(path: T) -> Option<SystemTime> {
+    let path = path.as_ref();
+    let own = fs::metadata(path).and_then(|meta| meta.modified()).ok();
+    if !path.is_dir() {
+        return own;
+    }
+    fs::read_dir(path).ok()?
+        .filter_map(|ent| ent.ok())
+        .filter_map(|ent| newest_mtime(ent.path()))
+        .chain(own)
+        .max()
+}
+
+/// Matches `text` against a shell-style pattern where `*` matches any run of
+/// characters and `?` matches exactly one.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let text = text.chars().collect::<Vec<_>>();
+    let (mut p, mut t) = (0, 0);
+    // position of the last '*' in the pattern and where in text it began
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((sp, st)) = star {
+            p = sp + 1;
+            t = st + 1;
+            star = Some((sp, st + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Expands a leading `~` in `value` to the home directory, as a shell
+/// would.
+pub fn expand_tilde(value: &str) -> String {
+    let rest = match value.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => rest,
+        _ => return value.to_string(),
+    };
+    match dirs_next::home_dir() {
+        Some(home) => format!("{}{}", home.display(), rest),
+        None => value.to_string(),
+    }
+}
+
+/// Name of this machine, as reported by `hostname`.
+pub fn hostname() -> String {
+    if let Ok(name) = env::var("HOSTNAME") {
+        if !name.is_empty() {
+            return name;
+        }
+    }
+    Command::new("hostname")
+        .output()
+        .ok()
+        .filter(|out| out.status.success())
+        .map(|out| String::from_utf8_lossy(&out.stdout).trim().to_string())
+        .or_else(|| fs::read_to_string("/etc/hostname").ok()
+            .map(|name| name.trim().to_string()))
+        .unwrap_or_else(|| String::from("localhost"))
+}
+
+/// Name of the user running dotflex.
+pub fn username() -> String {
+    env::var("USER")
+        .or_else(|_| env::var("LOGNAME"))
+        .ok()
+        .or_else(|| Command::new("id").arg("-un").output().ok()
+            .map(|out| String::from_utf8_lossy(&out.stdout).trim().to_string()))
+        .unwrap_or_default()
+}
+
 /// Resolve a path to an absolute path
 pub fn resolve_common<T: AsRef<Path>>(p: T) -> Option<PathBuf> {
     let p = p.as_ref();
@@ -73,10 +254,6 @@ pub fn resolve_path_target<T: AsRef<Path>>(p: T) -> PathBuf {
     resolve_common(&p)
         .unwrap_or(self::target_dir().join(&p))
 }
-pub fn resolve_path_local<T: AsRef<Path>>(p: T) -> PathBuf {
-    resolve_common(&p)
-        .unwrap_or(self::local_dir().join(&p))
-}
 
 pub fn unresolve_path<T: AsRef<Path>>(p: T) -> Option<PathBuf> {
     let p = p.as_ref();
@@ -104,13 +281,6 @@ pub fn unresolve_path_repo<T: AsRef<Path>>(p: T) -> PathBuf {
         } else { pb })
         .unwrap_or(p.as_ref().to_path_buf())
 }
-pub fn unresolve_path_local<T: AsRef<Path>>(p: T) -> PathBuf {
-    unresolve_path(&p)
-        .map(|pb| if pb.starts_with("@l") {
-            pb.strip_prefix("@l").unwrap().to_path_buf()
-        } else { pb })
-        .unwrap_or(p.as_ref().to_path_buf())
-}
 pub fn unresolve_path_target<T: AsRef<Path>>(p: T) -> PathBuf {
     unresolve_path(&p)
         .map(|pb| if pb.starts_with("@t") {
@@ -130,18 +300,10 @@ where
     match var {
         Some(pb) => pb.as_path(),
         None => {
-            let p = match env::var(env_name.as_ref()) {
-                Ok(val) => PathBuf::from(val),
-                Err(e) => {
-                    if let env::VarError::NotUnicode(_) = e {
-                        panic!(
-                            "environmental variable {:?} could not be parsed: {}",
-                            env_name.as_ref(),
-                            e
-                        );
-                    }
-                    default()
-                }
+            // paths needn't be unicode
+            let p = match env::var_os(env_name.as_ref()) {
+                Some(val) => PathBuf::from(val),
+                None => default(),
             };
             if !p.exists() {
                 if let Err(e) = fs::create_dir_all(&p) {
@@ -152,8 +314,7 @@ where
             if let Err(e) = canonical {
                 panic!("could not canonicalize path {}: {}", p.display(), e);
             }
-            var.insert(canonical.unwrap());
-            var.as_ref().unwrap()
+            var.insert(canonical.unwrap())
         }
     }
 }
@@ -215,3 +376,33 @@ pub fn target_dir() -> &'static Path
 pub fn target_path<T: AsRef<Path>>(path: T) -> PathBuf {
     assure_target().as_ref().unwrap().join(path)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::glob_match;
+
+    #[test]
+    fn matches_literally() {
+        assert!(glob_match("linux", "linux"));
+        assert!(!glob_match("linux", "linux2"));
+        assert!(!glob_match("linux2", "linux"));
+    }
+
+    #[test]
+    fn matches_wildcards() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("work-*", "work-laptop"));
+        assert!(glob_match("*-laptop", "work-laptop"));
+        assert!(glob_match("w*k*p", "work-laptop"));
+        assert!(glob_match("x86_6?", "x86_64"));
+        assert!(!glob_match("x86_6?", "x86_6"));
+        assert!(!glob_match("work-*", "home-laptop"));
+    }
+
+    #[test]
+    fn backtracks_over_stars() {
+        assert!(glob_match("*ab", "aab"));
+        assert!(glob_match("*a*b*c", "abxbyc"));
+        assert!(!glob_match("*a*b*c", "abxbyd"));
+    }
+}
@@ -0,0 +1,68 @@
+// -*- rust -*-
+// mod dotflex::watch
+//
+// Notices when target files are written, for `dotflex watch`. Editors
+// often save by writing a new file and renaming it over the old one, so
+// the directories holding the files are watched with inotify rather than
+// the files themselves. The events are read on a thread of their own and
+// passed on over a channel, so that the caller can wait on them with a
+// timeout.
+
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+use inotify::{Inotify, WatchMask};
+
+use super::error::{DotflexError, Result};
+
+// room for a few dozen events at a time
+const EVENT_BUFFER: usize = 4096;
+
+/// Starts watching `targets`; each time one of them is written, its path
+/// is sent on the returned channel. Files in directories that can't be
+/// watched are reported and left out. The channel gives an error, and
+/// then closes, if reading events fails.
+pub fn watch(targets: &[PathBuf]) -> Result<Receiver<io::Result<PathBuf>>> {
+    let mut inotify = Inotify::init()
+        .map_err(|e| DotflexError::io("<inotify>", e))?;
+    let mut dirs = HashMap::new();
+    for dir in targets.iter().filter_map(|target| target.parent()) {
+        if dirs.values().any(|watched| watched == dir) {
+            continue;
+        }
+        let mask = WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO | WatchMask::ONLYDIR;
+        match inotify.add_watch(dir, mask) {
+            Ok(wd) => { dirs.insert(wd, dir.to_path_buf()); },
+            Err(e) => eprintln!("warning: can't watch {}: {}", dir.display(), e),
+        }
+    }
+    let targets = targets.iter().cloned().collect::<HashSet<_>>();
+
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buffer = [0; EVENT_BUFFER];
+        loop {
+            let events = match inotify.read_events_blocking(&mut buffer) {
+                Ok(events) => events,
+                Err(e) => {
+                    let _ = tx.send(Err(e));
+                    return;
+                },
+            };
+            for event in events {
+                let path = match (dirs.get(&event.wd), event.name) {
+                    (Some(dir), Some(name)) => dir.join(name),
+                    _ => continue,
+                };
+                // stop once no one is listening
+                if targets.contains(&path) && tx.send(Ok(path)).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    Ok(rx)
+}
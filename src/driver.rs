@@ -1,129 +1,448 @@
-use crate::dotflex::{util, common, parser, sync};
-use super::dotflex::tracker::{Features, TrackedFeature};
-use super::dotflex::operation::{FeatureSchema, OperationSchema, ShellInvocation, OperationEffects, OperationInstance};
+use crate::dotflex::{util, common, parser, sync, backup, watch};
+use crate::dotflex::transaction::Transaction;
+use crate::dotflex::conflict::{self, ConflictPolicy, Resolution};
+use crate::dotflex::merge::{self, MergeOutcome};
+use crate::dotflex::config::{self, Config, SyncConfig};
+use crate::dotflex::sync::git;
+pub use crate::dotflex::sync::UpsyncOptions;
+use crate::dotflex::error::{DotflexError, Result};
+use super::dotflex::tracker::{Features, FileStatus, TrackedFeature};
+use super::dotflex::operation::{FeatureSchema, OperationSchema, OperationInstance, PlannedEffect};
 use std::path::{PathBuf, Path};
-use std::fs::{self, DirEntry};
+use std::fs;
+use std::collections::HashMap;
 use std::collections::hash_map::Entry;
-use clap::ArgMatches;
-use std::process::{exit, Command};
+use std::io::{self, BufRead, Write};
+use std::env;
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::{Duration, Instant};
 
-pub fn report_status() {
+/// Where `init` should point the local repo.
+pub enum Remote {
+    /// a git repository URL, with the remote name and branch to use for
+    /// it; those not given are found in an existing clone, or defaulted
+    Git {
+        url: String,
+        remote: Option<String>,
+        branch: Option<String>,
+    },
+    /// a plain directory to mirror the local repo to, e.g. on a mounted
+    /// drive
+    Directory(PathBuf),
+}
+
+/// A file to bind: `target` in the target directory, copied to `repo` in
+/// the local repo, or to the same relative path under the feature
+/// directory if `repo` is None.
+pub struct Binding {
+    pub target: PathBuf,
+    pub repo: Option<PathBuf>,
+}
+
+#[derive(Default)]
+pub struct BindOptions {
+    /// replace the target files with symlinks into the local repo
+    pub link: bool,
+    pub dry_run: bool,
+}
+
+#[derive(Default)]
+pub struct RebindOptions {
+    pub dry_run: bool,
+}
+
+#[derive(Default)]
+pub struct UnbindOptions {
+    /// don't delete the copies in the local repo
+    pub keep_repo: bool,
+    /// leave the files in the target directory alone
+    pub keep_target: bool,
+}
+
+#[derive(Default)]
+pub struct DownsyncOptions {
+    /// write conflicting upstream changes into locally edited target files
+    /// with conflict markers, rather than leaving those files alone
+    pub conflict_markers: bool,
+    /// leave active features alone, even if the downsync changed them
+    pub no_apply: bool,
+    /// ask before re-applying changed features
+    pub confirm: bool,
+    /// what to do when re-applying would overwrite a target file that
+    /// differs from its repo copy; asks if `confirm` is set, backs up
+    /// otherwise
+    pub conflict: Option<ConflictPolicy>,
+}
+
+#[derive(Default)]
+pub struct FeatureOptions {
+    pub dry_run: bool,
+    /// what to do when enabling would overwrite a target file that differs
+    /// from its repo copy
+    pub conflict: ConflictPolicy,
+}
+
+pub struct WatchOptions {
+    /// how long a file has to be left alone after a write before it is
+    /// rebound
+    pub debounce: Duration,
+    /// commit the local repo after rebinding, if the sync backend keeps a
+    /// history
+    pub commit: bool,
+    /// upsync this often
+    pub upsync_every: Option<Duration>,
+}
+
+impl Default for WatchOptions {
+    fn default() -> WatchOptions {
+        WatchOptions {
+            debounce: Duration::from_secs(2),
+            commit: false,
+            upsync_every: None,
+        }
+    }
+}
+
+pub fn report_status() -> Result<()> {
     println!("Target directory: {}", util::target_dir().display());
     println!("Config directory: {}", util::config_dir().display());
+    match sync::configured() {
+        Ok(backend) => match backend.status() {
+            Ok(status) => println!("Sync: {}", status),
+            Err(e) => println!("Sync: status unavailable: {}", e),
+        },
+        Err(_) => println!("Sync: not set up; see `dotflex init`"),
+    }
     println!();
     println!("Features:");
-    let feats = load_features();
+    let feats = load_features()?;
     if 0 == feats.expose().iter().count() {
         println!("  -- no features found.");
-        return;
+        return Ok(());
     }
     let longest_feature_name_len : usize = feats.expose()
         .keys()
         .max_by_key(|s| s.chars().count())
         .unwrap().chars().count();
     for (name, feat) in feats.expose().iter() {
-        println!("  {:<width$}: {}",
+        println!("  {:<width$}: {}{}",
             name,
             if feat.active() { "enabled" } else { "disabled" },
+            if feat.applicable() { "" } else { " (not applicable on this host)" },
             width = longest_feature_name_len);
         if !common::output_verbose() {
             continue;
         }
         for file in feat.files().iter() {
             let path = file.local_path().unwrap_or(Path::new("<no target path>"));
-            println!("    {} ({})",
-                path.display(),
-                util::resolve_path_target(path).display());
+            match file.link_state() {
+                Some(state) => println!("    {} ({}) [{}]",
+                    path.display(),
+                    util::resolve_path_target(path).display(),
+                    state),
+                None => println!("    {} ({})",
+                    path.display(),
+                    util::resolve_path_target(path).display()),
+            }
         }
     }
-    feats.dump_local();
+    feats.dump_local()
+}
+
+/// How a file bound by an active feature compares to its repo copy.
+pub struct BoundFileStatus {
+    pub feature: String,
+    /// the target path, as given in the feature's manifest
+    pub path: PathBuf,
+    pub status: FileStatus,
+}
+
+/// How every file bound by an active feature compares to its repo copy,
+/// ordered by feature.
+pub fn status() -> Result<Vec<BoundFileStatus>> {
+    let feats = load_features()?;
+    let mut names = feats.expose().values()
+        .filter(|feat| feat.active())
+        .map(|feat| feat.name())
+        .collect::<Vec<_>>();
+    names.sort();
+
+    let mut statuses = Vec::new();
+    for name in names {
+        let feat = &feats.expose()[name];
+        statuses.extend(feat.files().iter()
+            .filter_map(|file| Some(BoundFileStatus {
+                feature: name.clone(),
+                path: file.local_path()?.to_path_buf(),
+                status: file.status()?,
+            })));
+    }
+    Ok(statuses)
+}
+
+/// Collects edited target files into the local repo, then sends it.
+pub fn upsync(opts: &UpsyncOptions) -> Result<()> {
+    let backend = sync::configured()?;
+    collect(opts)?;
+    backend.upsync(opts)
 }
 
-pub fn upsync(args: &ArgMatches) {
-    if !util::repo_path(".git").exists() {
-        eprintln!("can't upsync: no local repo");
-        exit(1);
+/// Copies the target files of active features that have been edited since
+/// they were installed back into the local repo, so that what is sent is
+/// up to date. With `opts.confirm`, asks about each file first.
+fn collect(opts: &UpsyncOptions) -> Result<()> {
+    let mut features = load_features()?;
+    let mut names = features.expose().values()
+        .filter(|feat| feat.active())
+        .map(|feat| feat.name().clone())
+        .collect::<Vec<_>>();
+    names.sort();
+
+    let mut txn = Transaction::begin();
+    let mut collected = 0;
+    for name in names.iter() {
+        let feature = features.expose_mut().get_mut(name).unwrap();
+        let mut edited = Vec::new();
+        for file in feature.files().iter().filter(|file| !file.is_link()) {
+            let path = match file.local_path() {
+                Some(path) => path,
+                None => continue,
+            };
+            match file.status() {
+                Some(FileStatus::ModifiedInTarget) =>
+                    edited.push((path.to_path_buf(), file.repo_path().to_path_buf())),
+                Some(FileStatus::ModifiedInBoth) =>
+                    println!("  {}: {} was changed both here and in the repo; not collected",
+                        name, path.display()),
+                _ => (),
+            }
+        }
+        for (path, repo_path) in edited {
+            let schema = OperationSchema::CopyFile {
+                from: util::resolve_path_target(&path),
+                to: util::resolve_path_repo(&repo_path),
+                when: None,
+            };
+            let inst = OperationInstance::from(&schema);
+            if opts.confirm && !confirm(&format!("  {}: collect {}?", name, path.display()), true)? {
+                continue;
+            }
+            print!("  {}: {}... ", name, inst);
+            if let Err(e) = txn.execute(&inst) {
+                println!("failed");
+                return Err(abort(txn, e.in_operation(&schema)));
+            }
+            println!("ok");
+            let base = merge::base_path(name, util::resolve_path_target(&path));
+            if let Err(e) = txn.guard(base).and_then(|_| feature.record_installed_file(&path)) {
+                return Err(abort(txn, e));
+            }
+            collected += 1;
+        }
     }
-    let did_sync = sync::git::upsync(None);
-    if !did_sync {
-        eprintln!("failed to upsync!");
-        exit(1);
+    if collected == 0 {
+        return txn.commit();
     }
+    println!("{} edited file(s) collected into the repo", collected);
+    let result = txn.guard(util::local_path("features.yml"))
+        .and_then(|_| features.dump_local());
+    finish(txn, result)
 }
 
-pub fn downsync(args: &ArgMatches) {
-    if !util::repo_path(".git").exists() {
-        eprintln!("can't downsync: no local repo found at {}",
-            util::repo_dir().display());
-        exit(1);
+/// Pulls upstream changes into the local repo, then merges them into the
+/// target files of active features that have been edited locally, and
+/// re-applies the active features that changed.
+pub fn downsync(opts: &DownsyncOptions) -> Result<()> {
+    let changed = sync::configured()?.downsync()?;
+    let stopped = merge_upstream(opts)?;
+    if !opts.no_apply {
+        reapply(&changed, opts)?;
     }
-    let did_sync = sync::git::downsync(None);
-    if !did_sync {
-        eprintln!("failed to downsync");
-        exit(1);
+    if stopped.is_empty() {
+        Ok(())
+    } else {
+        Err(DotflexError::MergeConflicts(stopped))
     }
 }
 
-pub fn init(args: &ArgMatches) {
-    if args.is_present("git") {
-        let repo = args.value_of("git");
-        if repo.is_none() {
-            eprintln!("no value given for --git");
-            exit(1);
-        }
-        util::assure_path_to(util::repo_dir().parent().unwrap());
-        let git_init = Command::new("git")
-            .args(&["init", "-b", "master",
-            util::repo_dir().to_str().unwrap()])
-            .status();  
-        if !git_init.is_ok() || !git_init.unwrap().success() {
-            eprintln!("could not initialize git repository at {}", util::repo_dir().display());
-            exit(1);
-        }
-        let git_remote = Command::new("git")
-            .args(&["remote", "add", "upstream", repo.unwrap()])
-            .current_dir(util::repo_dir().as_path())
-            .status();
-        if !git_remote.is_ok() || !git_remote.unwrap().success() {
-            eprintln!("could not set up remote {} for git repository at {}", repo.unwrap(), util::repo_dir().display());
-            exit(1);
+/// Merges upstream changes into edited target files; returns the files
+/// left alone because of conflicts.
+fn merge_upstream(opts: &DownsyncOptions) -> Result<Vec<PathBuf>> {
+    let mut features = load_features()?;
+    let mut names = features.expose().values()
+        .filter(|feat| feat.active())
+        .map(|feat| feat.name().clone())
+        .collect::<Vec<_>>();
+    names.sort();
+
+    let mut txn = Transaction::begin();
+    let mut stopped = Vec::new();
+    for name in names.iter() {
+        let feature = features.expose_mut().get_mut(name).unwrap();
+        for file in feature.files_mut().iter_mut() {
+            let path = match file.local_path() {
+                Some(path) => path.to_path_buf(),
+                None => continue,
+            };
+            match merge::merge(&mut txn, name, file, opts.conflict_markers) {
+                Ok(MergeOutcome::Unchanged) => (),
+                Ok(MergeOutcome::Merged) =>
+                    println!("  {}: merged upstream changes into {}", name, path.display()),
+                Ok(MergeOutcome::Conflicts(n)) =>
+                    println!("  {}: {} conflict(s) marked in {}", name, n, path.display()),
+                Ok(MergeOutcome::Stopped(n)) => {
+                    println!("  {}: {} conflict(s) in {}; left as it is", name, n, path.display());
+                    stopped.push(path);
+                },
+                Ok(MergeOutcome::NoBase) =>
+                    println!("  {}: {} was changed both here and upstream, but there is nothing to merge against",
+                        name, path.display()),
+                Err(e) => return Err(abort(txn, e)),
+            }
         }
+    }
+    let result = txn.guard(util::local_path("features.yml"))
+        .and_then(|_| features.dump_local());
+    finish(txn, result)?;
+    Ok(stopped)
+}
+
+/// Re-installs the active features whose manifests or files are among the
+/// repo paths in `changed`, after showing what that would do. Target files
+/// edited since they were installed are left alone; merge_upstream has
+/// already brought upstream changes into them.
+fn reapply(changed: &[PathBuf], opts: &DownsyncOptions) -> Result<()> {
+    let mut features = load_features()?;
+    let changed = changed.iter()
+        .map(|path| util::repo_dir().join(path))
+        .collect::<Vec<_>>();
+    let names = features.expose().values()
+        .filter(|feat| feat.active() && feat.applicable())
+        .filter(|feat| {
+            let feat_dir = util::repo_path("features").join(feat.name());
+            changed.iter().any(|path| path.starts_with(&feat_dir)
+                || feat.files().iter().any(|file|
+                    util::resolve_path_repo(file.repo_path()) == *path))
+        })
+        .map(|feat| feat.name().as_str())
+        .collect::<Vec<_>>();
+    if names.is_empty() {
+        return Ok(());
+    }
+    let order = features.install_order(&names)?
+        .into_iter()
+        .filter(|feat| names.contains(&feat.as_str()))
+        .collect::<Vec<_>>();
+    let edited = order.iter()
+        .map(|feat| (feat.clone(), edited_targets(&features.expose()[feat])))
+        .collect::<HashMap<_, _>>();
+
+    let mut txn = Transaction::begin();
+    let preview = FeatureOptions { dry_run: true, ..FeatureOptions::default() };
+    for feat in order.iter() {
+        println!("Would re-apply feature {}:", feat);
+        install(&mut txn, &features.expose()[feat], &preview, &edited[feat])?;
+    }
+    if opts.confirm && !confirm("Re-apply these features?", false)? {
+        println!("features left as they are");
+        return txn.commit();
+    }
+
+    let conflict = opts.conflict.unwrap_or(if opts.confirm {
+        ConflictPolicy::Ask
     } else {
-        unreachable!();
+        ConflictPolicy::Backup
+    });
+    let apply = FeatureOptions { dry_run: false, conflict };
+    for feat in order.iter() {
+        println!("Re-applying feature {}:", feat);
+        let tracked_feat = features.expose_mut().get_mut(feat).unwrap();
+        let written = match install(&mut txn, tracked_feat, &apply, &edited[feat]) {
+            Ok(written) => written,
+            Err(e) => return Err(abort(txn, e)),
+        };
+        if let Err(e) = record_written(tracked_feat, &written) {
+            return Err(abort(txn, e));
+        }
     }
+    let result = txn.guard(util::local_path("features.yml"))
+        .and_then(|_| features.dump_local());
+    finish(txn, result)
 }
 
-pub fn bind(args: &ArgMatches) {
-    let feat = args.value_of("feature").expect("error: no feature name");
+/// The target files of copies made by `feat` that have been edited since
+/// they were installed.
+fn edited_targets(feat: &TrackedFeature) -> Vec<PathBuf> {
+    feat.files().iter()
+        .filter(|file| !file.is_link())
+        .filter_map(|file| {
+            let target = util::resolve_path_target(file.local_path()?);
+            match file.installed() {
+                Some(record) if !record.matches(&target) => Some(target),
+                _ => None,
+            }
+        })
+        .collect()
+}
 
+/// Asks a yes or no question on the terminal; an empty answer means
+/// `default`.
+fn confirm(question: &str, default: bool) -> Result<bool> {
+    print!("{} {} ", question, if default { "[Y/n]" } else { "[y/N]" });
+    let _ = io::stdout().flush();
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)
+        .map_err(|e| DotflexError::io("<stdin>", e))?;
+    Ok(match answer.trim() {
+        "" => default,
+        answer => matches!(answer, "y" | "yes"),
+    })
+}
+
+/// Sets up the local repo to sync with `remote`, and records the choice in
+/// LOCAL/config.yml.
+pub fn init(remote: &Remote) -> Result<()> {
+    let sync = match remote {
+        Remote::Git { url, remote, branch } => {
+            let (found_remote, found_branch) = git::detect(url);
+            SyncConfig::Git {
+                url: url.clone(),
+                remote: remote.clone().or(found_remote).unwrap_or_else(config::default_remote),
+                branch: branch.clone().or(found_branch).unwrap_or_else(config::default_branch),
+            }
+        },
+        Remote::Directory(path) => SyncConfig::Directory {
+            path: env::current_dir()
+                .map_err(|e| DotflexError::io(".", e))?
+                .join(path),
+        },
+    };
+    sync::backend(&sync).init()?;
+    let mut config = Config::load()?;
+    config.sync = Some(sync);
+    config.dump()
+}
+
+pub fn bind(feat: &str, bindings: &[Binding], opts: &BindOptions) -> Result<()> {
     let feat_dir = util::repo_path("features").join(feat);
-    if !util::assure_path(&feat_dir) {
-        eprintln!("error resolving feature directory {}",
-            feat_dir.display());
-        exit(1);
-    }
+    util::assure_path(&feat_dir)?;
 
-    let files = args.grouped_values_of("files");
-    if files.is_none() {
-        return;
+    if bindings.is_empty() {
+        return Ok(());
     }
 
     let mut operations: Vec<OperationSchema> = Vec::new();
 
-    let files = files.unwrap();
-    for binding in files {
-        if binding.len() > 2 {
-            eprintln!("too many arguments to option -f: expected 1 or 2");
-            exit(1);
-        }
-        let binding_target = util::resolve_path_target(binding[0]);
-        let binding_repo = if binding.len() == 2 {
-            util::resolve_common(binding[1])
-                .unwrap_or(feat_dir.join(binding[1]))
+    for binding in bindings {
+        let binding_target = util::resolve_path_target(&binding.target);
+        let binding_repo = if let Some(repo) = &binding.repo {
+            util::resolve_common(repo)
+                .unwrap_or(feat_dir.join(repo))
         } else {
             if !binding_target.starts_with(util::target_dir()) {
-                eprintln!("error: binding out-of-target file must be fully specified: {}", binding_target.display());
-                exit(1);
+                return Err(DotflexError::Usage(format!(
+                    "binding out-of-target file must be fully specified: {}",
+                    binding_target.display())));
             }
             feat_dir.join(binding_target
                     .strip_prefix(util::target_dir()).unwrap())
@@ -131,45 +450,78 @@ pub fn bind(args: &ArgMatches) {
 
         let op = OperationSchema::CopyFile {
             from: binding_target,
-            to: binding_repo
+            to: binding_repo,
+            when: None
         };
         operations.push(op);
     }
 
     for op in operations.iter() {
         if !op.is_viable() {
-            println!("not viable: {}", op);
-            exit(1);
+            return Err(DotflexError::NotViable { op: op.to_string() });
         }
     }
 
+    // with --link, the target is replaced by a link to its new repo copy
+    let link = opts.link;
+    let link_operations = operations.iter()
+        .filter(|_| link)
+        .map(|op| match op {
+            OperationSchema::CopyFile { from, to, .. }
+                => OperationSchema::LinkFile {
+                    from: to.clone(),
+                    to: from.clone(),
+                    when: None,
+                },
+            _ => { unreachable!(); }
+        })
+        .collect::<Vec<_>>();
+
+    if opts.dry_run {
+        println!("would bind...");
+        for op in operations.iter().chain(link_operations.iter()) {
+            print_plan(&OperationInstance::from(op));
+        }
+        let manifest = feat_dir.join("manifest.yml");
+        println!("  would {} {}", PlannedEffect::writing(&manifest), manifest.display());
+        return Ok(());
+    }
+
+    let mut features = load_features()?;
+
     println!("binding...");
-    for op in operations.iter() {
+    let mut txn = Transaction::begin();
+    for op in operations.iter().chain(link_operations.iter()) {
         let inst = OperationInstance::from(op);
         print!("  {}... ", inst);
-        if inst.execute() {
-            println!("ok");
-        } else {
+        if let Err(e) = txn.execute(&inst) {
             println!("failed");
-            exit(1);
+            return Err(abort(txn, e.in_operation(op)));
         }
+        println!("ok");
     }
 
-    let mut features = load_features();
     let feature = features.expose_mut().entry(feat.to_string());
 
     let operations = operations.iter()
         .map(|op| match op {
-            OperationSchema::CopyFile { from, to }
+            OperationSchema::CopyFile { from, to, .. } if link
+                => OperationSchema::LinkFile {
+                    from: util::unresolve_path_repo(to),
+                    to: util::unresolve_path_target(from),
+                    when: None,
+                },
+            OperationSchema::CopyFile { from, to, .. }
                 => OperationSchema::CopyFile {
                     from: util::unresolve_path_repo(to),
                     to: util::unresolve_path_target(from),
+                    when: None,
                 },
             _ => { unreachable!(); }
         })
         .collect::<Vec<_>>();
 
-    match feature {
+    let tracked = match feature {
         Entry::Occupied(mut e) => {
             for op in operations.iter() {
                 e.get_mut()
@@ -178,149 +530,747 @@ pub fn bind(args: &ArgMatches) {
                     .push(op.clone());
             }
             e.get_mut().update_files_from_schema();
+            e.into_mut()
         },
         Entry::Vacant(ve) => {
             println!("creating new feature {}...", feat);
             ve.insert(TrackedFeature::new(feat.to_string(), true,
-                    FeatureSchema::install(operations)));
+                    FeatureSchema::install(operations.clone())))
         }
+    };
+    let recorded = operations.iter()
+        .filter_map(|op| op.installed_target())
+        .map(|to| tracked.record_installed_file(to))
+        .collect::<Result<Vec<_>>>();
+
+    let manifest = feat_dir.join("manifest.yml");
+    let result = recorded
+        .and_then(|_| txn.guard(&manifest))
+        .and_then(|_| txn.guard(util::local_path("features.yml")))
+        .and_then(|_| parser::dump_manifest(
+            &manifest,
+            features.expose().get(&feat.to_string()).unwrap().schema()))
+        .and_then(|_| features.dump_local());
+    finish(txn, result)
+}
+
+/// Copies the named target files of `feat` back into their repo copies.
+/// With no `files`, every copied file of the feature is rebound.
+pub fn rebind<T: AsRef<Path>>(feat: &str, files: &[T], opts: &RebindOptions) -> Result<()> {
+    let mut features = load_features()?;
+    if !features.expose().contains_key(feat) {
+        return Err(DotflexError::NoSuchFeature(feat.to_string()));
+    }
+
+    let feat_dir = util::repo_path("features").join(feat);
+    if !feat_dir.exists() {
+        return Err(DotflexError::io(&feat_dir,
+            io::Error::from(io::ErrorKind::NotFound)));
     }
 
-    parser::dump_manifest(
-        &feat_dir.join("manifest.yml"),
-        features.expose().get(&feat.to_string()).unwrap().schema());
-    features.dump_local();
+    let files = files.iter()
+        .map(|file| file.as_ref().to_path_buf())
+        .collect();
+    rebind_features(&mut features, vec![(feat.to_string(), files)], opts)
 }
 
-pub fn rebind(args: &ArgMatches) {
-    let feat = args.value_of("feature").expect("error: no feature name");
+/// Rebinds every copied file of every active feature.
+pub fn rebind_all(opts: &RebindOptions) -> Result<()> {
+    let mut features = load_features()?;
+    let mut names = features.expose().values()
+        .filter(|feat| feat.active())
+        .map(|feat| (feat.name().clone(), Vec::new()))
+        .collect::<Vec<_>>();
+    names.sort();
+    rebind_features(&mut features, names, opts)
+}
 
-    let mut features = load_features();
-    let feature = features
-        .expose_mut()
-        .get_mut(&feat.to_string());
+/// Rebinds the given files of each feature, or all of its copied files
+/// where none are given; targets that match their repo copies are skipped.
+fn rebind_features(features: &mut Features, rebinds: Vec<(String, Vec<PathBuf>)>, opts: &RebindOptions) -> Result<()> {
+    let dry_run = opts.dry_run;
+    println!("{}", if dry_run { "would rebind..." } else { "rebinding..." });
+
+    let mut txn = Transaction::begin();
+    let mut summary = Vec::new();
+    for (feat, files) in rebinds {
+        let feature = features.expose_mut().get_mut(&feat).unwrap();
+        let named = !files.is_empty();
+        let files = if named {
+            files
+        } else {
+            // copies whose condition doesn't hold here weren't installed
+            feature.schema().install_operations().iter()
+                .filter(|op| op.applies())
+                .filter_map(|op| match op {
+                    OperationSchema::CopyFile { to, .. } => Some(to.clone()),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        let mut rebound = Vec::new();
+        for file in files.iter() {
+            let binding_target = util::resolve_path_target(file);
+            let binding_target = util::unresolve_path_target(binding_target);
+            let mut did_rebind = false;
+
+            for op in feature.schema().install_operations() {
+                match op {
+                    OperationSchema::CopyFile { from, to, .. } => {
+                        if binding_target.as_path() == to.as_path() {
+                            did_rebind = true;
+                            let target = util::resolve_path_target(to);
+                            let repo = util::resolve_path_repo(from);
+                            if util::same_contents(&target, &repo) {
+                                if named {
+                                    println!("  {} is unchanged", file.display());
+                                }
+                                continue;
+                            }
+                            // only named files are expected to be there
+                            if !named && !target.exists() {
+                                continue;
+                            }
+                            let schema = OperationSchema::CopyFile {
+                                    from: target,
+                                    to: repo,
+                                    when: None
+                                };
+                            let inst = OperationInstance::from(&schema);
+                            rebound.push(to.clone());
+                            if dry_run {
+                                print_plan(&inst);
+                                continue;
+                            }
+                            print!("  {}... ", inst);
+                            if let Err(e) = txn.execute(&inst) {
+                                println!("failed");
+                                return Err(abort(txn, e.in_operation(&schema)));
+                            }
+                            println!("ok");
+                        }
+                    },
+                    OperationSchema::LinkFile { to, .. } => {
+                        if binding_target.as_path() == to.as_path() {
+                            println!("  {} is linked into the repo; nothing to rebind", file.display());
+                            did_rebind = true;
+                        }
+                    },
+                    _ => ()
+                }
+            }
+            if !did_rebind {
+                println!("-- couldn't rebind: {}", file.display());
+            }
+        }
+        if !dry_run {
+            let recorded = rebound.iter()
+                .map(|to| feature.record_installed_file(to))
+                .collect::<Result<Vec<_>>>();
+            if let Err(e) = recorded {
+                return Err(abort(txn, e));
+            }
+        }
+        if !rebound.is_empty() {
+            summary.push((feat, rebound));
+        }
+    }
 
-    if feature.is_none() {
-        eprintln!("no such feature: {}", feat);
-        exit(1);
+    if summary.is_empty() {
+        println!("no files updated");
+        return txn.commit();
+    }
+    for (feat, rebound) in summary.iter() {
+        println!("{} {}: {}",
+            if dry_run { "would update" } else { "updated" },
+            feat,
+            rebound.iter()
+                .map(|to| to.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", "));
+    }
+    if dry_run {
+        return txn.commit();
     }
-    let feature = feature.unwrap();
+    let result = txn.guard(util::local_path("features.yml"))
+        .and_then(|_| features.dump_local());
+    finish(txn, result)
+}
+
+pub fn unbind<T: AsRef<Path>>(feat: &str, files: &[T], opts: &UnbindOptions) -> Result<()> {
+    let keep_repo = opts.keep_repo;
+    let keep_target = opts.keep_target;
+
+    let mut features = load_features()?;
+    let feature = features
+        .expose_mut()
+        .get_mut(&feat.to_string())
+        .ok_or_else(|| DotflexError::NoSuchFeature(feat.to_string()))?;
 
     let feat_dir = util::repo_path("features").join(feat);
     if !feat_dir.exists() {
-        eprintln!("could not find feature directory: {}", feat_dir.display());
-        exit(1);
+        return Err(DotflexError::io(&feat_dir,
+            io::Error::from(io::ErrorKind::NotFound)));
     }
 
-    let files = args.values_of("files");
-    if files.is_none() {
-        return;
+    if files.is_empty() {
+        return Ok(());
     }
 
-    println!("rebinding...");
+    println!("unbinding...");
 
-    let files = files.unwrap();
+    let backup = backup::Backup::begin(feat);
+    let mut txn = Transaction::begin();
+    let mut unbound: Vec<PathBuf> = Vec::new();
+    let mut unmatched = Vec::new();
     for file in files {
+        let file = file.as_ref();
         let binding_target = util::resolve_path_target(file);
         let binding_target = util::unresolve_path_target(binding_target);
-        let mut did_rebind = false;
-
-        for op in feature.schema().install_operations() {
-            match op {
-                OperationSchema::CopyFile { from, to } => {
-                    if binding_target.as_path() == to.as_path() {
-                        let schema = OperationSchema::CopyFile {
-                                from: util::resolve_path_target(to),
-                                to: util::resolve_path_repo(from)
-                            };
-                        let inst = OperationInstance::from(&schema);
-                        print!("  {}... ", inst);
-                        println!("{}", if inst.execute() {
-                            "ok"
-                        } else {
-                            "failed"
-                        });
-                        did_rebind = true;
+
+        let matching = feature.schema().install_operations().iter()
+            .filter(|op| match op {
+                OperationSchema::CopyFile { to, .. }
+                    | OperationSchema::LinkFile { to, .. } => to == &binding_target,
+                _ => false,
+            })
+            .map(|op| op.resolved())
+            .collect::<Vec<_>>();
+        if matching.is_empty() {
+            println!("-- couldn't unbind: {}", file.display());
+            unmatched.push(file.to_path_buf());
+            continue;
+        }
+
+        for op in matching.iter() {
+            let (repo, target, link) = match op {
+                OperationSchema::CopyFile { from, to, .. } => (from, to, false),
+                OperationSchema::LinkFile { from, to, .. } => (from, to, true),
+                _ => { unreachable!(); }
+            };
+            print!("  {}... ", util::unresolve_path_target(target).display());
+            if let Err(e) = txn.guard(target).and_then(|_| txn.guard(repo)) {
+                println!("failed");
+                return Err(abort(txn, e));
+            }
+            let is_our_link = link && util::links_to(target, repo);
+            let target_result = if keep_target {
+                // a link into the repo would dangle once the repo copy is gone
+                if is_our_link && !keep_repo {
+                    util::remove_path(target).and_then(|_|
+                        OperationInstance::from(&OperationSchema::CopyFile {
+                            from: repo.clone(),
+                            to: target.clone(),
+                            when: None
+                        }).execute())
+                } else {
+                    Ok(())
+                }
+            } else if link && !is_our_link {
+                // the user replaced the link with something else; leave it be
+                Ok(())
+            } else {
+                backup.save(target).and_then(|_| util::remove_path(target))
+            };
+            let result = target_result.and_then(|_| if keep_repo {
+                Ok(())
+            } else {
+                util::remove_path(repo)
+            });
+            if let Err(e) = result {
+                println!("failed");
+                return Err(abort(txn, e));
+            }
+            println!("ok");
+        }
+        unbound.push(binding_target);
+    }
+
+    feature.schema_mut()
+        .install_operations_mut()
+        .retain(|op| match op {
+            OperationSchema::CopyFile { to, .. }
+                | OperationSchema::LinkFile { to, .. } => !unbound.contains(to),
+            _ => true,
+        });
+    feature.update_files_from_schema();
+
+    let manifest = feat_dir.join("manifest.yml");
+    let result = txn.guard(&manifest)
+        .and_then(|_| txn.guard(util::local_path("features.yml")))
+        .and_then(|_| parser::dump_manifest(&manifest, feature.schema()))
+        .and_then(|_| features.dump_local());
+    finish(txn, result)?;
+    // the rest are unbound all the same, but scripts should notice
+    if unmatched.is_empty() {
+        Ok(())
+    } else {
+        Err(DotflexError::NotBound { feature: feat.to_string(), files: unmatched })
+    }
+}
+
+/// Enables the named features, along with the features they require. If
+/// any operation fails, everything done so far in this run is rolled back.
+pub fn enable(names: &[&str], opts: &FeatureOptions) -> Result<()> {
+    let mut features = load_features()?;
+    let dry_run = opts.dry_run;
+    // a feature that can't be enabled here doesn't need its requirements
+    let names = names.iter()
+        .copied()
+        .filter(|name| match features.expose().get(*name) {
+            Some(feat) if !feat.active() && !feat.applicable() => {
+                println!("Skipping feature {}: not applicable on this host", name);
+                false
+            },
+            _ => true,
+        })
+        .collect::<Vec<_>>();
+    let enabled_features = features.install_order(&names)?;
+    let mut txn = Transaction::begin();
+    for feat in enabled_features.iter() {
+        let tracked_feat = match features.expose().get(&feat.to_string()) {
+            Some(tracked_feat) if !tracked_feat.active() => tracked_feat,
+            _ => continue,
+        };
+        if !tracked_feat.applicable() {
+            println!("Skipping feature {}: not applicable on this host", tracked_feat.name());
+            continue;
+        }
+        println!("{} feature {}:",
+            if dry_run { "Would enable" } else { "Enabling" },
+            tracked_feat.name());
+        let written = match install(&mut txn, tracked_feat, opts, &[]) {
+            Ok(written) => written,
+            Err(e) => return Err(abort(txn, e)),
+        };
+        if !dry_run {
+            features.mark_active(feat);
+            let tracked_feat = features.expose_mut().get_mut(feat).unwrap();
+            if let Err(e) = record_written(tracked_feat, &written) {
+                return Err(abort(txn, e));
+            }
+        }
+    }
+    if dry_run {
+        return txn.commit();
+    }
+    let result = txn.guard(util::local_path("features.yml"))
+        .and_then(|_| features.dump_local());
+    finish(txn, result)
+}
+
+/// Disables the named features. If any operation fails, everything done so
+/// far in this run is rolled back.
+pub fn disable(names: &[&str], opts: &FeatureOptions) -> Result<()> {
+    let mut features = load_features()?;
+    let dry_run = opts.dry_run;
+    let disabled_features = names;
+    // reported up front, as enable does
+    let unknown = names.iter()
+        .filter(|name| !features.expose().contains_key(**name))
+        .map(|name| format!("no such feature: {}", name))
+        .collect::<Vec<_>>();
+    if !unknown.is_empty() {
+        return Err(DotflexError::Dependencies(unknown));
+    }
+    let mut txn = Transaction::begin();
+    for feat in disabled_features.iter() {
+        let tracked_feat = match features.expose().get(&feat.to_string()) {
+            Some(tracked_feat) if tracked_feat.active() => tracked_feat,
+            _ => {
+                println!("feature {} is not enabled", feat);
+                continue;
+            },
+        };
+        let dependents = features.dependents(feat).into_iter()
+            .filter(|d| !disabled_features.contains(d))
+            .collect::<Vec<_>>();
+        if !dependents.is_empty() {
+            eprintln!("warning: feature {} is required by enabled feature(s): {}",
+                feat, dependents.join(", "));
+        }
+        println!("{} feature {}:",
+            if dry_run { "Would disable" } else { "Disabling" },
+            tracked_feat.name());
+        let backup = backup::Backup::begin(feat);
+        let ops = tracked_feat.schema().uninstall_operations()
+            .iter()
+            .map(|op| op.resolved());
+        for op in ops {
+            let inst = OperationInstance::from(&op).in_feature(feat);
+            if !op.applies() {
+                println!("  {}... skipped (condition not met)", inst);
+                continue;
+            }
+            if dry_run {
+                print_plan(&inst);
+                continue;
+            }
+            print!("  {}... ", inst);
+            if let Err(e) = txn.execute(&inst) {
+                println!("failed");
+                return Err(abort(txn, e.in_operation(&op)));
+            }
+            println!("ok");
+        }
+        for op in tracked_feat.schema().install_operations().iter() {
+            if !op.applies() {
+                continue;
+            }
+            let op = op.resolved();
+            if let OperationSchema::AppendToFile { to, .. } = &op {
+                let shown = util::unresolve_path_target(to);
+                if dry_run {
+                    println!("  would remove appended block from {}", to.display());
+                    continue;
+                }
+                print!("  removing appended block from {}... ", shown.display());
+                let inst = OperationInstance::from(&op).in_feature(feat);
+                let result = txn.guard(to)
+                    .and_then(|_| backup.save(to))
+                    .and_then(|_| inst.remove_appended());
+                match result {
+                    Ok(true) => println!("ok"),
+                    Ok(false) => println!("not found"),
+                    Err(e) => {
+                        println!("failed");
+                        return Err(abort(txn, e));
                     }
-                },
-                _ => ()
+                }
+                continue;
+            }
+            let path = match op.installed_target() {
+                Some(path) => path,
+                None => continue,
+            };
+            let shown = util::unresolve_path_target(path);
+            if let OperationSchema::LinkFile { .. } = op {
+                // leave it be if the user replaced the link with a real file
+                if !util::is_symlink(path) {
+                    if path.exists() {
+                        println!("  leaving {} alone: no longer a link", shown.display());
+                    }
+                    continue;
+                }
+            }
+            if let OperationSchema::CopyFile { .. } = op {
+                // leave it be if it wasn't installed (say, skipped over a
+                // conflict) or the user edited it since
+                let record = tracked_feat.files().iter()
+                    .find(|file| file.local_path()
+                        .map_or(false, |local| util::resolve_path_target(local) == path))
+                    .and_then(|file| file.installed());
+                match record {
+                    _ if !path.exists() => continue,
+                    Some(record) if record.matches(path) => (),
+                    Some(_) => {
+                        eprintln!("warning: leaving {} alone: changed since it was installed", shown.display());
+                        continue;
+                    },
+                    None => {
+                        eprintln!("warning: leaving {} alone: not installed by this feature", shown.display());
+                        continue;
+                    },
+                }
             }
+            if dry_run {
+                if path.exists() {
+                    println!("  would {} {}", PlannedEffect::Delete, path.display());
+                }
+                continue;
+            }
+            print!("  removing {}... ", shown.display());
+            let result = txn.guard(path)
+                .and_then(|_| backup.save(path))
+                .and_then(|_| util::remove_path(path));
+            if let Err(e) = result {
+                println!("failed");
+                return Err(abort(txn, e));
+            }
+            println!("ok");
         }
-        if !did_rebind {
-            println!("-- couldn't rebind: {}", file);
+        if !dry_run {
+            features.mark_inactive(feat);
+            if let Err(e) = features.expose_mut().get_mut(*feat).unwrap().forget_installed() {
+                return Err(abort(txn, e));
+            }
         }
     }
+    if dry_run {
+        return txn.commit();
+    }
+    let result = txn.guard(util::local_path("features.yml"))
+        .and_then(|_| features.dump_local());
+    finish(txn, result)
 }
 
-pub fn enable(args: &ArgMatches) {
-    let features = load_features();
-    let enabled_features = args
-        .values_of("enable")
-        .unwrap_or(clap::Values::default())
+/// Restores the latest backup of a feature or of a single target file;
+/// lists the available backups if `what` is None.
+pub fn restore(what: Option<&str>) -> Result<()> {
+    let name = match what {
+        Some(name) => name,
+        None => {
+            let sets = backup::list();
+            if sets.is_empty() {
+                println!("no backups found.");
+            }
+            for set in sets.iter() {
+                println!("{} @ {}:", set.feature(), set.stamp());
+                for target in set.targets() {
+                    println!("  {}", target.display());
+                }
+            }
+            return Ok(());
+        }
+    };
+
+    let mut restored = Vec::new();
+    if let Some(set) = backup::latest_for_feature(name) {
+        println!("restoring feature {} from backup {}...", name, set.stamp());
+        for target in set.targets() {
+            restored.push((set.restore(&target), target));
+        }
+    } else {
+        let target = util::resolve_path_target(name);
+        let set = backup::latest_for_path(&target)
+            .ok_or_else(|| DotflexError::Usage(format!("no backup found for {}", name)))?;
+        println!("restoring from backup {} of feature {}...", set.stamp(), set.feature());
+        restored.push((set.restore(&target), target));
+    }
+
+    let mut first_error = None;
+    for (result, target) in restored.into_iter() {
+        print!("  {}... ", util::unresolve_path_target(&target).display());
+        match result {
+            Ok(()) => println!("ok"),
+            Err(e) => {
+                println!("failed");
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+    match first_error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Runs the install operations of `tracked_feat` in `txn`, settling
+/// conflicts as `opts.conflict` says, or with `opts.dry_run` prints what they
+/// would do. Copies onto the target files in `keep` are skipped. Returns the
+/// whole-file targets that were written, including those kept or merged.
+fn install(txn: &mut Transaction, tracked_feat: &TrackedFeature, opts: &FeatureOptions, keep: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let feat = tracked_feat.name();
+    let backup = backup::Backup::begin(feat);
+    let mut written = Vec::new();
+    let ops = tracked_feat.schema().install_operations()
+        .iter()
+        .map(|op| op.resolved());
+    for op in ops {
+        let inst = OperationInstance::from(&op).in_feature(feat);
+        if !op.applies() {
+            println!("  {}... skipped (condition not met)", inst);
+            continue;
+        }
+        if let OperationSchema::CopyFile { to, .. } = &op {
+            if keep.contains(to) {
+                println!("  {}... skipped (edited locally)", inst);
+                continue;
+            }
+        }
+        if opts.dry_run {
+            print_plan(&inst);
+            continue;
+        }
+        let resolution = match conflict::conflicting(&op) {
+            // keeping the target or merging writes to both files
+            Some((repo, target)) => txn.guard(repo)
+                .and_then(|_| txn.guard(target))
+                .and_then(|_| conflict::resolve(repo, target, opts.conflict))
+                .map_err(|e| e.in_operation(&op))?,
+            None => Resolution::Overwrite { backup: true },
+        };
+        print!("  {}... ", inst);
+        let result = match resolution {
+            Resolution::Overwrite { backup: true } =>
+                backup.save_clobbered(&op).and_then(|_| txn.execute(&inst)),
+            Resolution::Overwrite { backup: false } => txn.execute(&inst),
+            Resolution::Skip => {
+                println!("skipped (target differs)");
+                continue;
+            },
+            Resolution::KeepMine => {
+                println!("kept target, copied into the repo");
+                written.extend(op.installed_target().map(Path::to_path_buf));
+                continue;
+            },
+            Resolution::Merged => {
+                println!("merged");
+                written.extend(op.installed_target().map(Path::to_path_buf));
+                continue;
+            },
+        };
+        if let Err(e) = result {
+            println!("failed");
+            return Err(e.in_operation(&op));
+        }
+        println!("ok");
+        written.extend(op.installed_target().map(Path::to_path_buf));
+    }
+    Ok(written)
+}
+
+/// Records the files of `feat` whose targets are in `written` as installed.
+fn record_written(feat: &mut TrackedFeature, written: &[PathBuf]) -> Result<()> {
+    let files = feat.files().iter()
+        .filter_map(|file| file.local_path())
+        .filter(|path| written.contains(&util::resolve_path_target(path)))
+        .map(Path::to_path_buf)
         .collect::<Vec<_>>();
-    enabled_features.iter()
-        .filter_map(
-        |feat| {
-            let tracked_feat = features.expose()
-                .get(&feat.to_string())?;
-            if tracked_feat.active() {
-                None
-            } else {
-                Some(tracked_feat)
+    for file in files {
+        feat.record_installed_file(&file)?;
+    }
+    Ok(())
+}
+
+/// Watches the copied target files of active features, rebinding each one
+/// once writes to it have settled; see WatchOptions for committing and
+/// upsyncing as well. Runs until it is interrupted. Failures after it has
+/// started are reported and watching goes on.
+pub fn watch(opts: &WatchOptions) -> Result<()> {
+    let features = load_features()?;
+    let mut watched = HashMap::new();
+    for feat in features.expose().values().filter(|feat| feat.active()) {
+        for file in feat.files().iter().filter(|file| !file.is_link()) {
+            if let Some(path) = file.local_path() {
+                watched.insert(util::resolve_path_target(path),
+                    (feat.name().clone(), path.to_path_buf()));
             }
-        })
-        .for_each(|tracked_feat| {
-            println!("Enabling feature {}:", tracked_feat.name());
-            let ops = tracked_feat.schema().install_operations()
-                .iter()
-                .map(|op| match op {
-                    OperationSchema::CopyFile { from, to } =>
-                        OperationSchema::CopyFile {
-                            from: util::resolve_path_repo(from),
-                            to: util::resolve_path_target(to)
-                        },
-                    OperationSchema::AppendToFile { from, to } =>
-                        OperationSchema::AppendToFile {
-                            from: util::resolve_path_repo(from),
-                            to: util::resolve_path_target(to)
-                        },
-                    OperationSchema::ShellFile { cmd, effects } =>
-                        OperationSchema::ShellFile {
-                            cmd: ShellInvocation::from(
-                                     &util::resolve_path_repo(cmd.file()), cmd.args().iter().map(|s| s.as_str()).collect::<Vec<&str>>().as_slice()),
-                            effects: effects.clone()
-                        },
-                    OperationSchema::ShellString { cmd, effects } =>
-                        OperationSchema::ShellString {
-                            cmd: cmd.clone(),
-                            effects: effects.clone()
-                        }
-                });
-            for op in ops {
-                let inst = OperationInstance::from(&op);
-                print!("  {}... ", inst);
-                println!("{}", if inst.execute() {
-                    "ok"
-                } else {
-                    "failed"
-                });
+        }
+    }
+    if watched.is_empty() {
+        println!("no copied files in active features; nothing to watch");
+        return Ok(());
+    }
+    let backend = if opts.commit || opts.upsync_every.is_some() {
+        Some(sync::configured()?)
+    } else {
+        None
+    };
+    if let Some(backend) = backend.as_ref() {
+        if opts.commit && !backend.keeps_history() {
+            return Err(DotflexError::Usage(String::from(
+                "--commit needs a sync backend that keeps a history, such as git")));
+        }
+    }
+    let targets = watched.keys().cloned().collect::<Vec<_>>();
+    let events = watch::watch(&targets)?;
+    println!("watching {} file(s); interrupt to stop", targets.len());
+
+    // when each file was last written, until it is rebound
+    let mut pending: HashMap<PathBuf, Instant> = HashMap::new();
+    let mut next_upsync = opts.upsync_every.and_then(|every| Instant::now().checked_add(every));
+    loop {
+        let now = Instant::now();
+        let wake = pending.values()
+            .map(|written| *written + opts.debounce)
+            .chain(next_upsync)
+            .min();
+        let received = match wake {
+            Some(wake) => events.recv_timeout(wake.saturating_duration_since(now)),
+            None => events.recv().map_err(RecvTimeoutError::from),
+        };
+        match received {
+            Ok(Ok(path)) => { pending.insert(path, Instant::now()); },
+            Ok(Err(e)) => return Err(DotflexError::io("<inotify>", e)),
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+
+        let now = Instant::now();
+        let settled = pending.iter()
+            .filter(|(_, written)| **written + opts.debounce <= now)
+            .map(|(path, _)| path.clone())
+            .collect::<Vec<_>>();
+        if !settled.is_empty() {
+            let mut by_feature: HashMap<&str, Vec<&Path>> = HashMap::new();
+            for path in settled.iter() {
+                pending.remove(path);
+                let (feat, local_path) = &watched[path];
+                by_feature.entry(feat.as_str()).or_default().push(local_path.as_path());
             }
-        });
+            for (feat, files) in by_feature {
+                if let Err(e) = rebind(feat, &files, &RebindOptions::default()) {
+                    eprintln!("error: {}", e);
+                }
+            }
+            match &backend {
+                Some(backend) if opts.commit => {
+                    if let Err(e) = backend.commit(&UpsyncOptions::default()) {
+                        eprintln!("error: {}", e);
+                    }
+                },
+                _ => (),
+            }
+        }
+
+        match (next_upsync, &backend) {
+            (Some(due), Some(backend)) if due <= now => {
+                if let Err(e) = collect(&UpsyncOptions::default())
+                    .and_then(|_| backend.upsync(&UpsyncOptions::default())) {
+                    eprintln!("error: {}", e);
+                }
+                next_upsync = opts.upsync_every.and_then(|every| now.checked_add(every));
+            },
+            _ => (),
+        }
+    }
+}
+
+/// Commits `txn` if `result` is Ok, and rolls it back otherwise.
+fn finish(txn: Transaction, result: Result<()>) -> Result<()> {
+    match result {
+        Ok(()) => txn.commit(),
+        Err(e) => Err(abort(txn, e)),
+    }
+}
+
+/// Rolls back `txn` after a failure, passing the original error on; a
+/// rollback that fails as well is only reported.
+fn abort(txn: Transaction, err: DotflexError) -> DotflexError {
+    println!("rolling back...");
+    if let Err(e) = txn.rollback() {
+        eprintln!("warning: rollback incomplete: {}", e);
+    }
+    err
+}
+
+/// Prints what an operation would do, with absolute paths, for `--dry-run`.
+fn print_plan(inst: &OperationInstance) {
+    println!("  {}", inst.schema());
+    if let Some(source) = inst.schema().source() {
+        println!("    from {}", source.display());
+    }
+    let planned = inst.plan();
+    if planned.is_empty() {
+        println!("    (no declared effects)");
+    }
+    for (path, effect) in planned.iter() {
+        println!("    would {} {}", effect, path.display());
+    }
 }
 
-fn load_features() -> Features {
-    let mut feats = Features::load_local();
+/// Loads the tracked features from LOCAL, updated with the manifests found
+/// in the local repo.
+pub fn load_features() -> Result<Features> {
+    let mut feats = Features::load_local()?;
 
     let features_path = util::repo_path("features");
     if features_path.exists() && features_path.is_dir() {
-        let features_dir = fs::read_dir(&features_path);
-        if let Err(e) = features_dir {
-            eprintln!("couldn't read features directory at {}: {}",
-                features_path.display(),
-                e);
-            std::process::exit(1);
-        }
-        let features_from_dir = features_dir.unwrap()
+        let features_dir = fs::read_dir(&features_path)
+            .map_err(|e| DotflexError::io(&features_path, e))?;
+        let features_from_dir = features_dir
             .filter_map(|res|
                 res.map_or(None,
                     |ent| Some((ent.path(), ent.path().strip_prefix(&features_path).unwrap().to_path_buf()))
@@ -336,17 +1286,14 @@ fn load_features() -> Features {
             let entry = feats.expose_mut().entry(feature_name.clone());
             match entry {
                 Entry::Occupied(mut e) => {
-                    e.insert(TrackedFeature::new(
-                            e.get().name().clone(),
-                            e.get().active(),
-                            parser::parse_manifest(&manifest)));
+                    e.get_mut().insert_schema(parser::parse_manifest(&manifest)?);
                 },
                 Entry::Vacant(ve) => {
                     println!("found unrecorded feature: {}!", &feature_name);
 
-                    let feat = TrackedFeature::new(feature_name.clone(), false, parser::parse_manifest(&manifest));
+                    let feat = TrackedFeature::new(feature_name.clone(), false, parser::parse_manifest(&manifest)?);
 
-                    parser::dump_manifest(&manifest, feat.schema());
+                    parser::dump_manifest(&manifest, feat.schema())?;
 
                     ve.insert(feat);
                 }
@@ -354,5 +1301,5 @@ fn load_features() -> Features {
         }
     }
 
-    feats
+    Ok(feats)
 }
@@ -0,0 +1,42 @@
+//! dotflex as a library: the same operations as the `dotflex` command line
+//! tool, for use from other tools.
+//!
+//! The target, config, repo and local directories are taken from the
+//! `DOTFLEX_TARGET_PATH` and `DOTFLEX_CONFIG_PATH` environment variables, as
+//! for the command line tool. The functions in [`driver`] print their
+//! progress to stdout, like the command line tool does; set
+//! [`set_output_verbosity`] for more detail.
+
+mod dotflex;
+pub mod driver;
+
+pub use crate::dotflex::common::set_output_verbosity;
+pub use crate::dotflex::condition::Condition;
+pub use crate::dotflex::conflict::ConflictPolicy;
+pub use crate::dotflex::error::{DotflexError, Result};
+pub use crate::dotflex::operation::{
+    FeatureSchema,
+    OperationSchema,
+    OperationEffects,
+    ShellInvocation,
+};
+pub use crate::dotflex::tracker::{
+    Features,
+    TrackedFeature,
+    InstalledFile,
+    FileRecord,
+    FileStatus,
+    LinkState,
+};
+pub use crate::driver::{
+    Binding,
+    BindOptions,
+    BoundFileStatus,
+    DownsyncOptions,
+    FeatureOptions,
+    RebindOptions,
+    Remote,
+    UnbindOptions,
+    UpsyncOptions,
+    WatchOptions,
+};
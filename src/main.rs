@@ -1,14 +1,12 @@
-#![allow(unused_imports)]
-#![allow(unused_variables)]
-#![allow(dead_code)]
-
-mod dotflex;
-mod driver;
-
 extern crate clap;
-use clap::{Arg, ArgGroup, App};
+use clap::{Arg, ArgGroup, App, ArgMatches};
 
-use dotflex::{common, util};
+use dotflex::driver::{self, Binding, BindOptions, DownsyncOptions, FeatureOptions, RebindOptions, Remote, UnbindOptions, UpsyncOptions, WatchOptions};
+use dotflex::{set_output_verbosity, ConflictPolicy, DotflexError, Result};
+use std::io::{self, IsTerminal};
+use std::path::PathBuf;
+use std::process::exit;
+use std::time::Duration;
 
 fn main() {
     let mut cli_commands : Vec<App> = Vec::new();
@@ -26,39 +24,119 @@ fn main() {
             .takes_value(true)
             // doesn't work properly with ArgMatches::grouped_values_of
             //.max_values(2)
-            .about("files to add to local repository")));
+            .about("files to add to local repository"))
+        .arg(Arg::new("link")
+            .long("link")
+            .about("replace the bound files with symlinks into the local repository"))
+        .arg(Arg::new("dry-run")
+            .long("dry-run")
+            .about("show what would be copied without copying anything")));
     cli_commands.push(App::new("rebind")
-        .about("rebinds specified files")
+        .about("copies changed target files back into the repo")
         .arg(Arg::new("feature")
             .takes_value(true)
-            .required(true)
+            .required_unless_present("all")
             .index(1)
             .about("which feature to bind files to"))
         .arg(Arg::new("files")
             .index(2)
             .multiple(true)
             .takes_value(true)
-            .about("files to rebind")));
-    // cli_commands.push(App::new("remove")
-    //     .about("controls automated un-binding of files")
-    //     .arg(Arg::new("files")
-    //         .multiple(true)
-    //         .required(true)
-    //         .about("files to remove from local repository")
-    //         .index(1)));
+            .about("files to rebind (default: all of the feature's copied files)"))
+        .arg(Arg::new("all")
+            .long("all")
+            .conflicts_with("feature")
+            .about("rebind the copied files of every active feature"))
+        .arg(Arg::new("dry-run")
+            .long("dry-run")
+            .about("show what would be copied without copying anything")));
+    cli_commands.push(App::new("unbind")
+        .about("controls automated un-binding of files")
+        .arg(Arg::new("feature")
+            .takes_value(true)
+            .required(true)
+            .index(1)
+            .about("which feature to unbind files from"))
+        .arg(Arg::new("files")
+            .index(2)
+            .multiple(true)
+            .required(true)
+            .takes_value(true)
+            .about("files to remove from local repository"))
+        .arg(Arg::new("keep-repo")
+            .long("keep-repo")
+            .about("don't delete the copies in the local repository"))
+        .arg(Arg::new("keep-target")
+            .long("keep-target")
+            .about("leave the files in the target directory alone")));
+    cli_commands.push(App::new("restore")
+        .about("restores target files from the backups taken when features were enabled")
+        .arg(Arg::new("target")
+            .index(1)
+            .takes_value(true)
+            .about("feature or file to restore; lists backups if omitted")));
+    cli_commands.push(App::new("status")
+        .about("compares bound files in the target directory with the local repository"));
     cli_commands.push(App::new("upsync")
-        .about("uploads dotfiles to repo specified with init"));
+        .about("uploads dotfiles to repo specified with init")
+        .arg(Arg::new("message")
+            .short('m')
+            .long("message")
+            .takes_value(true)
+            .about("commit message to use instead of a generated one"))
+        .arg(Arg::new("edit")
+            .short('e')
+            .long("edit")
+            .about("edit the commit message in $EDITOR")));
     cli_commands.push(App::new("downsync")
-        .about("downloads dotfiles from repo specified with init"));
+        .about("downloads dotfiles from repo specified with init")
+        .arg(Arg::new("conflict-markers")
+            .long("conflict-markers")
+            .about("write conflicting upstream changes into locally edited files with conflict markers"))
+        .arg(Arg::new("no-apply")
+            .long("no-apply")
+            .about("don't re-apply active features that the downsync changed"))
+        .arg(Arg::new("conflict")
+            .long("conflict")
+            .takes_value(true)
+            .possible_values(&["ask", "overwrite", "skip", "backup"])
+            .about("what to do with target files that differ from the repo when re-applying; asks if run from a terminal, backs up otherwise")));
+    cli_commands.push(App::new("watch")
+        .about("rebinds copied files of active features whenever they are edited")
+        .arg(Arg::new("debounce")
+            .long("debounce")
+            .takes_value(true)
+            .about("seconds to wait after a file is written before rebinding it (default: 2)"))
+        .arg(Arg::new("commit")
+            .long("commit")
+            .about("commit the local repo after each rebind"))
+        .arg(Arg::new("upsync-every")
+            .long("upsync-every")
+            .takes_value(true)
+            .about("upsync every so many minutes")));
     cli_commands.push(App::new("init")
         .about("sets up remote repository for dotfiles")
         .arg(Arg::new("git")
             .long("git")
             .takes_value(true)
             .about("use git repository"))
-        .group(ArgGroup::new("remote")
+        .arg(Arg::new("remote")
+            .long("remote")
+            .takes_value(true)
+            .requires("git")
+            .about("name of the git remote (default: found in an existing clone, or upstream)"))
+        .arg(Arg::new("branch")
+            .long("branch")
+            .takes_value(true)
+            .requires("git")
+            .about("branch to sync (default: found in an existing clone, or master)"))
+        .arg(Arg::new("dir")
+            .long("dir")
+            .takes_value(true)
+            .about("mirror to a directory, e.g. on a mounted drive"))
+        .group(ArgGroup::new("backend")
             .required(true)
-            .args(&["git"])));
+            .args(&["git", "dir"])));
     cli_commands.push(App::new("feature")
         .about("activate and deactivate features")
         // .arg(Arg::new("only-files")
@@ -71,13 +149,21 @@ fn main() {
             .multiple(true)
             .number_of_values(1)
             .about("enable a feature"))
-        // .arg(Arg::new("disable")
-        //     .short('d')
-        //     .long("disable")
-        //     .takes_value(true)
-        //     .multiple(true)
-        //     .number_of_values(1)
-        //     .about("disable a feature"))
+        .arg(Arg::new("disable")
+            .short('d')
+            .long("disable")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .about("disable a feature"))
+        .arg(Arg::new("dry-run")
+            .long("dry-run")
+            .about("show what would be done without touching any files"))
+        .arg(Arg::new("conflict")
+            .long("conflict")
+            .takes_value(true)
+            .possible_values(&["ask", "overwrite", "skip", "backup"])
+            .about("what to do with target files that differ from the repo; asks if run from a terminal, backs up otherwise"))
         );
 
     let cli_args = App::new("dotflex")
@@ -91,39 +177,168 @@ fn main() {
         .get_matches();
     
     let use_verbose = cli_args.is_present("verbose");
-    common::set_output_verbosity(use_verbose);
+    set_output_verbosity(use_verbose);
 
-    match cli_args.subcommand() {
+    let result = match cli_args.subcommand() {
         Some(("bind", subcli_args)) => {
-            driver::bind(subcli_args)
+            bind(subcli_args)
         },
         Some(("rebind", subcli_args)) => {
-            driver::rebind(subcli_args)
+            let opts = RebindOptions {
+                dry_run: subcli_args.is_present("dry-run"),
+            };
+            match subcli_args.value_of("feature") {
+                Some(feat) => driver::rebind(feat, &values(subcli_args, "files"), &opts),
+                None => driver::rebind_all(&opts),
+            }
         }
-        Some(("remove", subcli_args)) => {},
+        Some(("restore", subcli_args)) => {
+            driver::restore(subcli_args.value_of("target"))
+        },
+        Some(("unbind", subcli_args)) => {
+            driver::unbind(
+                subcli_args.value_of("feature").expect("error: no feature name"),
+                &values(subcli_args, "files"),
+                &UnbindOptions {
+                    keep_repo: subcli_args.is_present("keep-repo"),
+                    keep_target: subcli_args.is_present("keep-target"),
+                })
+        },
+        Some(("status", _)) => {
+            status()
+        },
         Some(("upsync", subcli_args)) => {
-            driver::upsync(subcli_args)
+            driver::upsync(&UpsyncOptions {
+                message: subcli_args.value_of("message").map(String::from),
+                edit: subcli_args.is_present("edit"),
+                confirm: io::stdin().is_terminal(),
+            })
         },
         Some(("downsync", subcli_args)) => {
-            driver::downsync(subcli_args)
+            subcli_args.value_of("conflict")
+                .map(str::parse)
+                .transpose()
+                .and_then(|conflict| driver::downsync(&DownsyncOptions {
+                    conflict_markers: subcli_args.is_present("conflict-markers"),
+                    no_apply: subcli_args.is_present("no-apply"),
+                    confirm: io::stdin().is_terminal(),
+                    conflict,
+                }))
         },
         Some(("init", subcli_args)) => {
-            driver::init(subcli_args)
+            match (subcli_args.value_of("git"), subcli_args.value_of("dir")) {
+                (Some(repo), _) => driver::init(&Remote::Git {
+                    url: repo.to_string(),
+                    remote: subcli_args.value_of("remote").map(String::from),
+                    branch: subcli_args.value_of("branch").map(String::from),
+                }),
+                (None, Some(dir)) => driver::init(&Remote::Directory(PathBuf::from(dir))),
+                _ => Err(DotflexError::Usage(String::from("no value given for --git or --dir"))),
+            }
         },
         Some(("feature", subcli_args)) => {
-            // let enables : Vec<&'_ str> = subcli_args
-            //     .values_of("enable")
-            //     .unwrap_or(clap::Values::default())
-            //     .collect();
-            // let disables : Vec<&'_ str> = subcli_args
-            //     .values_of("disable")
-            //     .unwrap_or(clap::Values::default())
-            //     .collect();
-            driver::enable(subcli_args)
+            feature(subcli_args)
+        },
+        Some(("watch", subcli_args)) => {
+            watch(subcli_args)
         },
         _ => {
-            driver::report_status();
+            driver::report_status()
         }
+    };
+    if let Err(e) = result {
+        eprintln!("error: {}", e);
+        exit(1);
     }
     println!("Done.");
 }
+
+fn values<'a>(args: &'a ArgMatches, name: &str) -> Vec<&'a str> {
+    args.values_of(name)
+        .unwrap_or(clap::Values::default())
+        .collect()
+}
+
+fn bind(args: &ArgMatches) -> Result<()> {
+    let feat = args.value_of("feature").expect("error: no feature name");
+    let mut bindings = Vec::new();
+    if let Some(files) = args.grouped_values_of("files") {
+        for binding in files {
+            if binding.len() > 2 {
+                return Err(DotflexError::Usage(String::from(
+                    "too many arguments to option -f: expected 1 or 2")));
+            }
+            bindings.push(Binding {
+                target: PathBuf::from(binding[0]),
+                repo: binding.get(1).map(PathBuf::from),
+            });
+        }
+    }
+    driver::bind(feat, &bindings, &BindOptions {
+        link: args.is_present("link"),
+        dry_run: args.is_present("dry-run"),
+    })
+}
+
+fn feature(args: &ArgMatches) -> Result<()> {
+    let conflict = match args.value_of("conflict") {
+        Some(policy) => policy.parse()?,
+        None if io::stdin().is_terminal() => ConflictPolicy::Ask,
+        None => ConflictPolicy::Backup,
+    };
+    let opts = FeatureOptions {
+        dry_run: args.is_present("dry-run"),
+        conflict,
+    };
+    if args.is_present("disable") {
+        driver::disable(&values(args, "disable"), &opts)?;
+    }
+    if args.is_present("enable") {
+        driver::enable(&values(args, "enable"), &opts)?;
+    }
+    Ok(())
+}
+
+fn watch(args: &ArgMatches) -> Result<()> {
+    let number = |name: &str| -> Result<Option<u64>> {
+        args.value_of(name)
+            .map(|value| value.parse().map_err(|_| DotflexError::Usage(
+                format!("--{} takes a whole number, not {}", name, value))))
+            .transpose()
+    };
+    let mut opts = WatchOptions::default();
+    if let Some(secs) = number("debounce")? {
+        opts.debounce = Duration::from_secs(secs);
+    }
+    opts.commit = args.is_present("commit");
+    opts.upsync_every = match number("upsync-every")? {
+        Some(0) => return Err(DotflexError::Usage(
+            String::from("--upsync-every takes a number of minutes greater than 0"))),
+        Some(mins) => Some(mins.checked_mul(60)
+            .map(Duration::from_secs)
+            .ok_or_else(|| DotflexError::Usage(format!("--upsync-every {} is too long", mins)))?),
+        None => None,
+    };
+    driver::watch(&opts)
+}
+
+fn status() -> Result<()> {
+    let statuses = driver::status()?;
+    let mut feature = None;
+    for file in statuses.iter() {
+        if feature != Some(&file.feature) {
+            println!("{}:", file.feature);
+            feature = Some(&file.feature);
+        }
+        println!("  {:<18} {}", file.status.to_string(), file.path.display());
+    }
+    let drifted = statuses.iter()
+        .filter(|file| file.status.drifted())
+        .count();
+    if drifted > 0 {
+        println!("{} file(s) drifted", drifted);
+        // exit non-zero so this can be used in scripts
+        exit(1);
+    }
+    Ok(())
+}